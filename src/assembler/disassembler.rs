@@ -0,0 +1,117 @@
+use crate::opcode::{OpCode, OpCode::*, SHIFT_IMMEDIATE};
+
+/// Renders bytecode as one `.lr` instruction per line, prefixed with its offset.
+/// Jump and call targets are printed as immediates since label names aren't kept in bytecode.
+pub fn disassemble(code: &[u8]) -> String {
+    let mut out = String::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        match disassemble_instruction(&code[pc..]) {
+            Some((text, size)) => {
+                out.push_str(&format!("{:04}: {}\n", pc, text));
+                pc += size;
+            }
+            None => {
+                out.push_str(&format!("{:04}: ; truncated: {:02X?}\n", pc, &code[pc..]));
+                break;
+            }
+        }
+    }
+    out
+}
+
+/// Renders the instruction at the start of `code`, returning it with its size in bytes,
+/// or `None` if `code` ends partway through it.
+pub fn disassemble_instruction(code: &[u8]) -> Option<(String, usize)> {
+    let word = code.get(0..4)?;
+    let opcode = OpCode::from(word[0]);
+    let (a, b, c) = (word[1], word[2], word[3]);
+    let imm = ((b as u16) << 8) | c as u16;
+    let operands = match opcode {
+        LOAD => format!("${} #{}", a, imm),
+        CALL | PRTS | SYSCALL => format!("#{}", ((a as u16) << 8) | b as u16),
+        JMP | JMPF | JMPB | JEQ | JNE | INC | DEC | ALOC | PUSH | POP => format!("${}", a),
+        ADD | SUB | MUL | DIV | AND | OR | XOR => format!("${} ${} ${}", a, b, c),
+        ADDF64 | SUBF64 | MULF64 | DIVF64 => format!("$f{} $f{} $f{}", a, b, c),
+        EQ | NEQ | GT | LT | GTE | LTE | NOT => format!("${} ${}", a, b),
+        EQF64 | NEQF64 | GTF64 | GTEF64 | LTF64 | LTEF64 => format!("$f{} $f{}", a, b),
+        SHL | SHR if b & SHIFT_IMMEDIATE != 0 => {
+            format!("${} #{} ${}", a, b & !SHIFT_IMMEDIATE, c)
+        }
+        SHL | SHR => format!("${} ${} ${}", a, b, c),
+        LOADM | SETM if c == 0 => format!("${} ${}", a, b),
+        LOADM | SETM => format!("${} ${} #{}", a, b, c),
+        LOADF64 => {
+            let mut value = [0u8; 8];
+            value.copy_from_slice(code.get(4..12)?);
+            // Debug formatting keeps the decimal point so the literal reassembles as a float
+            format!("$f{} #{:?}", a, f64::from_be_bytes(value))
+        }
+        HLT | RET | NOP => String::new(),
+        _ => format!("; {:02X?}", word),
+    };
+    let mnemonic = opcode.to_string().to_lowercase();
+    let size = if opcode == LOADF64 { 12 } else { 4 };
+    if operands.is_empty() {
+        Some((mnemonic, size))
+    } else {
+        Some((format!("{} {}", mnemonic, operands), size))
+    }
+}
+
+/// Tests for disassembler
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::{Assembler, AssemblerError};
+
+    #[test]
+    fn test_disassemble() {
+        let code = vec![
+            LOAD as u8, 0, 1, 0, //
+            SHL as u8, 0, SHIFT_IMMEDIATE | 4, 1, //
+            CALL as u8, 0, 16, 0, //
+            HLT as u8, 0, 0, 0,
+        ];
+        assert_eq!(
+            disassemble(&code),
+            "0000: load $0 #256\n0004: shl $0 #4 $1\n0008: call #16\n0012: hlt\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_truncated() {
+        let code = vec![INC as u8, 0, 0, 0, LOADF64 as u8, 1, 0, 0, 0x40];
+        assert_eq!(
+            disassemble(&code),
+            "0000: inc $0\n0004: ; truncated: [16, 01, 00, 00, 40]\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_round_trip() -> Result<(), Vec<AssemblerError>> {
+        let source = r".data
+.code
+    load $0 #10
+    loadf64 $f1 #2.5
+    addf64 $f1 $f1 $f2
+    gtef64 $f2 $f1
+    shr $0 $1 $2
+    setm $0 $1 #2
+    loadm $3 $0
+    push $3
+    call #36
+    hlt";
+        let mut asm = Assembler::new();
+        let bytecode = asm.assemble(source).map_err(|e| e.clone())?.clone();
+
+        let listing = disassemble(&bytecode);
+        let mut reassembled = String::from(".data\n.code\n");
+        for line in listing.lines() {
+            reassembled.push_str(&format!("    {}\n", &line[6..]));
+        }
+        let mut asm = Assembler::new();
+        assert_eq!(asm.assemble(&reassembled).map_err(|e| e.clone())?, &bytecode);
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
-use super::{parser::Token, AssemblerError, SymbolTable};
-use byteorder::{LittleEndian, WriteBytesExt};
+use super::{parser::Token, span::Span, AssemblerError, SymbolTable};
+use super::SymbolType;
+use crate::opcode::{OpCode, OperandKind, REGISTER_COUNT, SHIFT_IMMEDIATE};
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct AssemblerInstruction {
@@ -7,6 +9,13 @@ pub struct AssemblerInstruction {
     pub operands: [Option<Token>; 3],
     pub label: Option<Token>,
     pub directive: Option<Token>,
+    /// Where the instruction is in the source, without its trailing newline
+    pub span: Span,
+    pub operand_spans: [Option<Span>; 3],
+    /// A directive's comma-separated arguments and where each was found
+    pub arguments: Vec<(Token, Span)>,
+    /// Comments after the label and at the end of the instruction
+    pub comments: Vec<Comment>,
 }
 
 impl AssemblerInstruction {
@@ -21,6 +30,50 @@ impl AssemblerInstruction {
             operands,
             label,
             directive,
+            span: Span::default(),
+            operand_spans: [None; 3],
+            arguments: vec![],
+            comments: vec![],
+        }
+    }
+
+    /// Sets the operands together with where each was found.
+    pub fn set_operands(&mut self, operands: [Option<(Token, Span)>; 3]) {
+        for (i, operand) in operands.into_iter().enumerate() {
+            let (token, span) = operand.unzip();
+            self.operands[i] = token;
+            self.operand_spans[i] = span;
+        }
+    }
+
+    /// Moves every span `offset` bytes further into the source.
+    pub fn shift(mut self, offset: usize) -> Self {
+        self.span = self.span.shift(offset);
+        for span in self.operand_spans.iter_mut().flatten() {
+            *span = span.shift(offset);
+        }
+        for (_, span) in &mut self.arguments {
+            *span = span.shift(offset);
+        }
+        for comment in &mut self.comments {
+            comment.span = comment.span.shift(offset);
+        }
+        self
+    }
+
+    /// Where operand `index` is, falling back to the whole instruction.
+    pub fn operand_span(&self, index: usize) -> Span {
+        self.operand_spans[index].unwrap_or(self.span)
+    }
+
+    /// Where the label declaration is, falling back to the whole instruction.
+    pub fn label_span(&self) -> Span {
+        match &self.label {
+            // Declarations always start the instruction
+            Some(Token::LabelDeclaration { name }) => {
+                Span::new(self.span.start, self.span.start + name.len())
+            }
+            _ => self.span,
         }
     }
 
@@ -48,12 +101,9 @@ impl AssemblerInstruction {
     }
 
     pub fn get_string_constant(&self) -> Option<String> {
-        match &self.operands[0] {
-            Some(d) => match d {
-                Token::IRString { name } => Some(name.to_string()),
-                _ => None,
-            },
-            None => None,
+        match self.arguments.first() {
+            Some((Token::IRString { name }, _)) => Some(name.to_string()),
+            _ => None,
         }
     }
 
@@ -65,67 +115,252 @@ impl AssemblerInstruction {
         }
     }
 
+    /// Number of bytes this instruction occupies once assembled: one 4-byte word,
+    /// followed by an 8-byte trailer for each f64 immediate.
+    pub fn size(&self) -> u32 {
+        if !self.is_instruction() {
+            return 0;
+        }
+        4 + 8 * self.operands.iter().flatten().filter(|t| self.is_wide(t)).count() as u32
+    }
+
+    /// Whether the operand is encoded after the instruction word rather than inside it.
+    /// LOADF64 promotes integer immediates so `loadf64 $f0 #2` loads 2.0.
+    fn is_wide(&self, token: &Token) -> bool {
+        match token {
+            Token::FloatOperand { .. } => true,
+            Token::IntegerOperand { .. } => {
+                self.opcode == Some(Token::Op { code: OpCode::LOADF64 })
+            }
+            _ => false,
+        }
+    }
+
+    fn is_shift(&self) -> bool {
+        matches!(
+            self.opcode,
+            Some(Token::Op {
+                code: OpCode::SHL | OpCode::SHR
+            })
+        )
+    }
+
+    fn is_memory_access(&self) -> bool {
+        matches!(
+            self.opcode,
+            Some(Token::Op {
+                code: OpCode::LOADM | OpCode::SETM
+            })
+        )
+    }
+
+    /// Checks the operands against the opcode's signature, reporting every mismatch.
+    /// `symbols` tells code labels from data labels.
+    pub fn check_operands(&self, symbols: &SymbolTable) -> Vec<AssemblerError> {
+        let opcode = match self.opcode {
+            Some(Token::Op { code }) => code,
+            _ => return vec![],
+        };
+        let signature = match opcode.signature() {
+            Some(signature) => signature,
+            None => {
+                return vec![AssemblerError::UnimplementedOpcode {
+                    opcode,
+                    span: self.span,
+                }]
+            }
+        };
+        let count = self.operands.iter().flatten().count();
+        if !signature.accepts(count) {
+            return vec![AssemblerError::WrongOperandCount {
+                opcode,
+                expected: signature.required..=signature.operands.len(),
+                found: count,
+                span: self.span,
+            }];
+        }
+
+        let mut errors = vec![];
+        for (i, (token, accepted)) in self.operands.iter().zip(signature.operands).enumerate() {
+            let token = match token {
+                Some(token) => token,
+                None => continue,
+            };
+            let kind = match token {
+                Token::LabelUsage { name } => match symbols.symbol_type(name) {
+                    Some(SymbolType::Data) => Some(OperandKind::DataLabel),
+                    Some(SymbolType::Label) => Some(OperandKind::Label),
+                    // Undefined labels are reported when encoding
+                    None => continue,
+                },
+                _ => token.operand_kind(),
+            };
+            if !kind.is_some_and(|kind| accepted.contains(&kind)) {
+                errors.push(AssemblerError::WrongOperandKind {
+                    opcode,
+                    expected: accepted.to_vec(),
+                    found: kind,
+                    span: self.operand_span(i),
+                });
+            } else if let Token::Register { id } | Token::FloatRegister { id } = token {
+                if *id >= REGISTER_COUNT {
+                    errors.push(AssemblerError::InvalidRegister {
+                        register: *id,
+                        span: self.operand_span(i),
+                    });
+                }
+            }
+        }
+        errors
+    }
+
     pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, AssemblerError> {
         let mut results = vec![];
+        let mut trailer = vec![];
         if let Some(ref token) = self.opcode {
             match token {
-                Token::Op { code } => match code {
-                    _ => {
-                        let b: u8 = (*code).into();
-                        results.push(b);
-                    }
-                },
+                Token::Op { code } => {
+                    let b: u8 = (*code).into();
+                    results.push(b);
+                }
                 _ => {
-                    return Err(AssemblerError::NonOpcodeInOpcodeField);
+                    return Err(AssemblerError::NonOpcodeInOpcodeField { span: self.span });
                 }
             }
         }
 
-        for operand in &self.operands {
-            if let Some(token) = operand {
-                AssemblerInstruction::extract_operand(token, &mut results, symbols);
+        for (i, token) in self.operands.iter().enumerate() {
+            let token = match token {
+                Some(token) => token,
+                None => continue,
+            };
+            match token {
+                Token::FloatOperand { value } => trailer.extend(value.to_be_bytes()),
+                Token::IntegerOperand { value, sign_bit } if self.is_wide(token) => {
+                    let value = if *sign_bit {
+                        -(*value as f64)
+                    } else {
+                        *value as f64
+                    };
+                    trailer.extend(value.to_be_bytes());
+                }
+                // Shift counts are packed into a single byte so the three-operand form still fits
+                Token::IntegerOperand { value, sign_bit } if self.is_shift() => {
+                    if *sign_bit || *value > (!SHIFT_IMMEDIATE) as u32 {
+                        return Err(AssemblerError::InvalidShiftAmount {
+                            amount: if *sign_bit {
+                                -(*value as i64)
+                            } else {
+                                *value as i64
+                            },
+                            span: self.operand_span(i),
+                        });
+                    }
+                    results.push(SHIFT_IMMEDIATE | *value as u8);
+                }
+                // The access width of LOADM/SETM is likewise a single byte
+                Token::IntegerOperand { value, sign_bit } if self.is_memory_access() => {
+                    match (*sign_bit, *value) {
+                        (false, 1 | 2 | 4) => results.push(*value as u8),
+                        _ => {
+                            return Err(AssemblerError::InvalidMemoryWidth {
+                                width: if *sign_bit {
+                                    -(*value as i64)
+                                } else {
+                                    *value as i64
+                                },
+                                span: self.operand_span(i),
+                            })
+                        }
+                    }
+                }
+                _ => AssemblerInstruction::extract_operand(
+                    token,
+                    self.operand_span(i),
+                    &mut results,
+                    symbols,
+                )?,
             }
         }
         while results.len() < 4 {
             results.push(0);
         }
+        results.append(&mut trailer);
         Ok(results)
     }
 
-    pub fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
+    /// Encodes a register, immediate or label operand found at `span`.
+    pub fn extract_operand(
+        t: &Token,
+        span: Span,
+        results: &mut Vec<u8>,
+        symbols: &SymbolTable,
+    ) -> Result<(), AssemblerError> {
         match t {
-            Token::Register { id } => {
+            Token::Register { id } | Token::FloatRegister { id } => {
                 results.push(*id);
             }
             Token::IntegerOperand { value, sign_bit } => {
                 let val = if *sign_bit {
-                    -(*value as i16)
+                    -(*value as i64)
                 } else {
-                    *value as i16
+                    *value as i64
                 };
+                // The 16-bit field holds anything that fits as either an i16 or a u16
+                if val < i16::MIN as i64 || val > u16::MAX as i64 {
+                    return Err(AssemblerError::InvalidOperand {
+                        operand: t.to_string(),
+                        span,
+                    });
+                }
                 let mut wtr = vec![];
-                wtr.write_i16::<LittleEndian>(val).unwrap();
-                results.push(wtr[1]);
-                results.push(wtr[0]);
+                wtr.write_u16::<BigEndian>(val as u16).unwrap();
+                results.extend(wtr);
             }
-            Token::LabelUsage { name } => {
-                if let Some(value) = symbols.get_symbol_offset(name) {
+            Token::LabelUsage { name } => match symbols.get_symbol_offset(name) {
+                Some(value) if value > u16::MAX as u32 => {
+                    return Err(AssemblerError::LabelOutOfRange {
+                        name: name.to_owned(),
+                        offset: value,
+                        span,
+                    });
+                }
+                Some(value) => {
                     let mut wtr = vec![];
                     wtr.write_u32::<LittleEndian>(value).unwrap();
                     results.push(wtr[1]);
                     results.push(wtr[0]);
-                } else {
-                    panic!("No value found for {:?}", name);
                 }
-            }
+                None => {
+                    return Err(AssemblerError::UndefinedLabel {
+                        name: name.to_owned(),
+                        span,
+                    });
+                }
+            },
             _ => {
-                panic!("Opcode found in operand field");
+                return Err(AssemblerError::InvalidOperand {
+                    operand: t.to_string(),
+                    span,
+                });
             }
         };
+        Ok(())
     }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
     pub instructions: Vec<AssemblerInstruction>,
+    /// Comments on lines of their own. Those sharing a line with an instruction are
+    /// kept on the instruction.
+    pub comments: Vec<Comment>,
+}
+
+/// A `;` or `//` comment, kept so tools like formatters can reproduce it
+#[derive(Debug, PartialEq, Clone)]
+pub struct Comment {
+    /// The comment including its leading `;` or `//`
+    pub text: String,
+    pub span: Span,
 }
@@ -1,9 +1,17 @@
+pub mod disassembler;
 pub mod instruction;
 pub mod parser;
+pub mod span;
 
-pub use instruction::Program;
+pub use instruction::{Comment, Program};
+pub use span::Span;
+
+use std::ops::RangeInclusive;
 
 use self::instruction::AssemblerInstruction;
+use self::parser::Token;
+use crate::executable::Executable;
+use crate::opcode::{OpCode, OperandKind, REGISTER_COUNT};
 
 #[derive(Debug, PartialEq)]
 pub enum AssemblerPhase {
@@ -12,51 +20,199 @@ pub enum AssemblerPhase {
     Second,
 }
 
+/// An assembler error and where in the source it was found
 #[derive(Debug, PartialEq, Clone)]
 pub enum AssemblerError {
-    NoSegmentDeclarationFound { instruction: u32 },
-    StringConstantDeclaredWithoutLabel { instruction: u32 },
-    SymbolAlreadyDeclared { name: String },
-    UnknownDirectiveFound { directive: String },
-    NonOpcodeInOpcodeField,
-    InsufficientSections,
-    UnknownSectionHeader { header: String },
-    ParseError { error: String },
+    NoSegmentDeclarationFound { line: usize, span: Span },
+    StringConstantDeclaredWithoutLabel { line: usize, span: Span },
+    SymbolAlreadyDeclared { name: String, span: Span },
+    UnknownDirectiveFound { directive: String, span: Span },
+    NonOpcodeInOpcodeField { span: Span },
+    InsufficientSections { span: Span },
+    UnknownSectionHeader { header: String, span: Span },
+    InvalidShiftAmount { amount: i64, span: Span },
+    InvalidMemoryWidth { width: i64, span: Span },
+    UndefinedLabel { name: String, span: Span },
+    LabelOutOfRange { name: String, offset: u32, span: Span },
+    InvalidOperand { operand: String, span: Span },
+    WrongOperandCount {
+        opcode: OpCode,
+        expected: RangeInclusive<usize>,
+        found: usize,
+        span: Span,
+    },
+    WrongOperandKind {
+        opcode: OpCode,
+        expected: Vec<OperandKind>,
+        found: Option<OperandKind>,
+        span: Span,
+    },
+    UnimplementedOpcode { opcode: OpCode, span: Span },
+    InvalidRegister { register: u8, span: Span },
+    InvalidDirectiveArgument { directive: String, span: Span },
+    DataValueOutOfRange { value: i64, width: u8, span: Span },
+    ParseError { error: String, span: Span },
+}
+
+impl AssemblerError {
+    pub fn span(&self) -> Span {
+        match self {
+            AssemblerError::NoSegmentDeclarationFound { span, .. }
+            | AssemblerError::StringConstantDeclaredWithoutLabel { span, .. }
+            | AssemblerError::SymbolAlreadyDeclared { span, .. }
+            | AssemblerError::UnknownDirectiveFound { span, .. }
+            | AssemblerError::NonOpcodeInOpcodeField { span }
+            | AssemblerError::InsufficientSections { span }
+            | AssemblerError::UnknownSectionHeader { span, .. }
+            | AssemblerError::InvalidShiftAmount { span, .. }
+            | AssemblerError::InvalidMemoryWidth { span, .. }
+            | AssemblerError::UndefinedLabel { span, .. }
+            | AssemblerError::LabelOutOfRange { span, .. }
+            | AssemblerError::InvalidOperand { span, .. }
+            | AssemblerError::WrongOperandCount { span, .. }
+            | AssemblerError::WrongOperandKind { span, .. }
+            | AssemblerError::UnimplementedOpcode { span, .. }
+            | AssemblerError::InvalidRegister { span, .. }
+            | AssemblerError::InvalidDirectiveArgument { span, .. }
+            | AssemblerError::DataValueOutOfRange { span, .. }
+            | AssemblerError::ParseError { span, .. } => *span,
+        }
+    }
+
+    /// Renders the error rustc-style, quoting the line of `source` it points at.
+    /// `name` identifies the source, usually its file path.
+    pub fn render(&self, name: &str, source: &str) -> String {
+        span::render_diagnostic(&self.to_string(), name, source, self.span())
+    }
 }
 
 impl std::fmt::Display for AssemblerError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            AssemblerError::NoSegmentDeclarationFound { instruction } => {
+            AssemblerError::NoSegmentDeclarationFound { line, .. } => {
                 write!(
                     f,
-                    "No segment declaration found for instruction {}",
-                    instruction
+                    "No segment declaration found before the label on line {}",
+                    line
                 )
             }
-            AssemblerError::StringConstantDeclaredWithoutLabel { instruction } => {
+            AssemblerError::StringConstantDeclaredWithoutLabel { line, .. } => {
                 write!(
                     f,
-                    "String constant declared without label in instruction {}",
-                    instruction
+                    "String constant declared without label on line {}",
+                    line
                 )
             }
-            AssemblerError::SymbolAlreadyDeclared { name } => {
+            AssemblerError::SymbolAlreadyDeclared { name, .. } => {
                 write!(f, "Symbol already declared: {}", name)
             }
-            AssemblerError::UnknownDirectiveFound { directive } => {
+            AssemblerError::UnknownDirectiveFound { directive, .. } => {
                 write!(f, "Unknown directive found: {}", directive)
             }
-            AssemblerError::UnknownSectionHeader { header } => {
+            AssemblerError::UnknownSectionHeader { header, .. } => {
                 write!(f, "Unknown section header: {}", header)
             }
-            AssemblerError::NonOpcodeInOpcodeField => {
+            AssemblerError::InvalidShiftAmount { amount, .. } => {
+                write!(f, "Invalid shift amount {} (expected 0 to 127)", amount)
+            }
+            AssemblerError::InvalidMemoryWidth { width, .. } => {
+                write!(f, "Invalid memory access width {} (expected 1, 2 or 4)", width)
+            }
+            AssemblerError::UndefinedLabel { name, .. } => {
+                write!(f, "Undefined label: {}", name)
+            }
+            AssemblerError::LabelOutOfRange { name, offset, .. } => {
+                write!(
+                    f,
+                    "Label {} is at {}, past the 16-bit operand limit of {}",
+                    name, offset, u16::MAX
+                )
+            }
+            AssemblerError::InvalidOperand { operand, .. } => {
+                write!(f, "Invalid operand ({})", operand)
+            }
+            AssemblerError::WrongOperandCount {
+                opcode,
+                expected,
+                found,
+                ..
+            } => {
+                let mnemonic = opcode.to_string().to_lowercase();
+                let plural = if *expected.end() == 1 { "" } else { "s" };
+                if expected.start() == expected.end() {
+                    write!(
+                        f,
+                        "{} takes {} operand{}, found {}",
+                        mnemonic,
+                        expected.end(),
+                        plural,
+                        found
+                    )
+                } else {
+                    write!(
+                        f,
+                        "{} takes {} to {} operand{}, found {}",
+                        mnemonic,
+                        expected.start(),
+                        expected.end(),
+                        plural,
+                        found
+                    )
+                }
+            }
+            AssemblerError::WrongOperandKind {
+                opcode,
+                expected,
+                found,
+                ..
+            } => {
+                let mut expected: Vec<String> = expected.iter().map(|k| k.to_string()).collect();
+                let last = expected.pop().unwrap_or_default();
+                let expected = if expected.is_empty() {
+                    last
+                } else {
+                    format!("{} or {}", expected.join(", "), last)
+                };
+                write!(
+                    f,
+                    "Expected {} operand for {}, found {}",
+                    expected,
+                    opcode.to_string().to_lowercase(),
+                    match found {
+                        Some(kind) => kind.to_string(),
+                        None => "something else".to_string(),
+                    }
+                )
+            }
+            AssemblerError::UnimplementedOpcode { opcode, .. } => {
+                write!(
+                    f,
+                    "{} is reserved and not implemented by the VM",
+                    opcode.to_string().to_lowercase()
+                )
+            }
+            AssemblerError::InvalidRegister { register, .. } => {
+                write!(
+                    f,
+                    "No register {} (registers go from 0 to {})",
+                    register,
+                    REGISTER_COUNT - 1
+                )
+            }
+            AssemblerError::InvalidDirectiveArgument { directive, .. } => {
+                write!(f, "Invalid argument for .{}", directive)
+            }
+            AssemblerError::DataValueOutOfRange { value, width, .. } => {
+                let plural = if *width == 1 { "" } else { "s" };
+                write!(f, "Value {} doesn't fit in {} byte{}", value, width, plural)
+            }
+            AssemblerError::NonOpcodeInOpcodeField { .. } => {
                 write!(f, "Non-opcode in opcode field")
             }
-            AssemblerError::InsufficientSections => {
-                write!(f, "Insufficient sections")
+            AssemblerError::InsufficientSections { .. } => {
+                write!(f, "Insufficient sections (expected .data and .code)")
             }
-            AssemblerError::ParseError { error } => {
+            AssemblerError::ParseError { error, .. } => {
                 write!(f, "Parse error: {}", error)
             }
         }
@@ -72,6 +228,8 @@ pub struct Assembler {
     pub program: Program,
     pub bytecode: Vec<u8>,
     ro_offset: u32,
+    ro_fixups: Vec<DataFixup>,
+    code_offset: u32,
     sections: Vec<AssemblerSection>,
     current_section: Option<AssemblerSection>,
     current_instruction: u32,
@@ -87,9 +245,12 @@ impl Assembler {
             code: String::new(),
             program: Program {
                 instructions: vec![],
+                comments: vec![],
             },
             bytecode: vec![],
             ro_offset: 0,
+            ro_fixups: vec![],
+            code_offset: 0,
             sections: vec![],
             current_section: None,
             current_instruction: 0,
@@ -111,9 +272,20 @@ impl Assembler {
         Ok(bytecode)
     }
 
+    /// Packages the most recently assembled program as an executable. Execution starts at
+    /// the `main` label when one is declared, otherwise at the first instruction.
+    pub fn to_executable(&self) -> Executable {
+        let entry = self.symbols.get_symbol_offset("main").unwrap_or(0);
+        Executable::new(self.bytecode.clone(), self.ro.clone(), entry)
+    }
+
     pub fn last_instruction(&self) -> Vec<u8> {
         let len = self.bytecode.len();
-        match self.bytecode.get(len-4..len) {
+        let size = match self.program.instructions.last() {
+            Some(instruction) => instruction.size() as usize,
+            None => return vec![],
+        };
+        match self.bytecode.get(len.saturating_sub(size)..len) {
             Some(instruction) => instruction.to_vec(),
             None => vec![],
         }
@@ -131,22 +303,18 @@ impl Assembler {
         }
     }
 
+    /// Spans in errors and instructions are byte offsets into `code`, the source
+    /// assembled so far.
     fn process_parse_phase(&mut self) -> &mut Self {
-        let mut code = String::new();
-        for line in self.code.lines() {
-            if line.trim().len() != 0 {
-                code.push_str(&(line.to_owned() + "\n"));
-            }
-        }
-        self.code = code;
         match parser::parse_program(&self.code) {
             Ok(program) => {
                 self.program = program;
                 self.phase = AssemblerPhase::First;
             }
-            Err(e) => {
+            Err(span) => {
                 self.errors.push(AssemblerError::ParseError {
-                    error: e.to_string(),
+                    error: "expected an instruction or directive".to_string(),
+                    span,
                 });
             }
         }
@@ -156,19 +324,26 @@ impl Assembler {
     fn process_first_phase(&mut self) -> &mut Self {
         self.symbols = SymbolTable::new();
         self.sections = vec![];
+        self.current_instruction = 0;
+        self.code_offset = 0;
+        self.ro = vec![];
+        self.ro_offset = 0;
+        self.ro_fixups = vec![];
         for i in self.program.instructions.clone() {
             if i.is_label() {
                 if self.current_section.is_some() {
                     self.process_label_declaration(&i);
                 } else {
                     self.errors.push(AssemblerError::NoSegmentDeclarationFound {
-                        instruction: self.current_instruction,
+                        line: i.span.line(&self.code),
+                        span: i.label_span(),
                     });
                 }
             }
             if i.is_directive() {
                 self.process_directive(&i);
             }
+            self.code_offset += i.size();
             self.current_instruction += 1;
         }
         self.phase = AssemblerPhase::Second;
@@ -177,19 +352,46 @@ impl Assembler {
 
     fn process_second_phase(&mut self) -> &mut Self {
         if self.sections.len() != 2 {
-            self.errors.push(AssemblerError::InsufficientSections);
+            self.errors.push(AssemblerError::InsufficientSections {
+                span: Span::new(self.code.len(), self.code.len()),
+            });
             return self;
         }
+        for fixup in std::mem::take(&mut self.ro_fixups) {
+            match self.symbols.get_symbol_offset(&fixup.name) {
+                Some(value) => match encode_data(value as i64, fixup.width, fixup.span) {
+                    Ok(bytes) => {
+                        self.ro[fixup.offset..fixup.offset + bytes.len()].copy_from_slice(&bytes)
+                    }
+                    Err(e) => self.errors.push(e),
+                },
+                None => self.errors.push(AssemblerError::UndefinedLabel {
+                    name: fixup.name,
+                    span: fixup.span,
+                }),
+            }
+        }
         let mut program: Vec<u8> = vec![];
+        self.current_instruction = 0;
         for i in &self.program.instructions {
             if i.is_instruction() {
-                program.append(&mut match i.to_bytes(&self.symbols) {
-                    Ok(bytes) => bytes,
-                    Err(e) => {
-                        self.errors.push(e);
-                        return self;
+                // Keep going so every bad instruction is reported in one pass
+                let mismatches = i.check_operands(&self.symbols);
+                if !mismatches.is_empty() {
+                    self.errors.extend(mismatches);
+                } else {
+                    match i.to_bytes(&self.symbols) {
+                        Ok(mut bytes) => program.append(&mut bytes),
+                        // Quote the operand as it was written
+                        Err(AssemblerError::InvalidOperand { span, .. }) => {
+                            self.errors.push(AssemblerError::InvalidOperand {
+                                operand: span.text(&self.code).to_string(),
+                                span,
+                            })
+                        }
+                        Err(e) => self.errors.push(e),
                     }
-                });
+                }
             }
             self.current_instruction += 1;
         }
@@ -203,68 +405,67 @@ impl Assembler {
             None => {
                 self.errors
                     .push(AssemblerError::StringConstantDeclaredWithoutLabel {
-                        instruction: self.current_instruction,
+                        line: i.span.line(&self.code),
+                        span: i.span,
                     });
                 return;
             }
         };
 
-        if self.symbols.has_symbol(&name) {
-            self.errors.push(AssemblerError::SymbolAlreadyDeclared { name: name.to_owned() });
+        if self.symbols.has_symbol(name) {
+            self.errors.push(AssemblerError::SymbolAlreadyDeclared {
+                name: name.to_owned(),
+                span: i.label_span(),
+            });
             return;
         }
-        let section_start = match self.current_section {
-            Some(AssemblerSection::Code {
-                starting_instruction,
-            }) => starting_instruction,
-            Some(AssemblerSection::Data {
-                starting_instruction,
-            }) => starting_instruction,
+        // Data labels point into the read-only data, code labels into the code
+        let (symbol_type, offset) = match self.current_section {
+            Some(AssemblerSection::Code { .. }) => (SymbolType::Label, self.code_offset),
+            Some(AssemblerSection::Data { .. }) => (SymbolType::Data, self.ro_offset),
             Some(AssemblerSection::Unknown) => {
                 return;
             }
             None => {
                 self.errors.push(AssemblerError::NoSegmentDeclarationFound {
-                    instruction: self.current_instruction,
+                    line: i.span.line(&self.code),
+                    span: i.label_span(),
                 });
                 return;
             }
         };
-        self.symbols.add_symbol(Symbol::new(
-            name,
-            SymbolType::Label,
-            (self.current_instruction * 4) - (section_start + 4),
-        ));
+        self.symbols
+            .add_symbol(Symbol::new(name, symbol_type, offset));
     }
 
     fn process_directive(&mut self, i: &AssemblerInstruction) {
-        let directive_name = match i.directive_name() {
-            Some(name) => name,
-            None => "",
-        };
-        if i.has_operands() {
-            match directive_name {
-                "asciiz" => {
-                    self.handle_asciiz(i);
-                }
-                _ => {
-                    self.errors.push(AssemblerError::UnknownDirectiveFound {
-                        directive: directive_name.to_owned(),
-                    });
-                }
+        let directive_name = i.directive_name().unwrap_or_default();
+        match directive_name {
+            "asciiz" => self.handle_asciiz(i),
+            "ascii" => self.handle_ascii(i),
+            "byte" => self.handle_data(i, 1),
+            "half" => self.handle_data(i, 2),
+            "word" => self.handle_data(i, 4),
+            "space" => self.handle_space(i),
+            "align" => self.handle_align(i),
+            _ if i.arguments.is_empty() => self.process_section_header(directive_name, i.span),
+            _ => {
+                self.errors.push(AssemblerError::UnknownDirectiveFound {
+                    directive: directive_name.to_owned(),
+                    span: i.span,
+                });
             }
-        } else {
-            self.process_section_header(&directive_name);
         }
     }
 
-    fn process_section_header(&mut self, name: &str) {
+    fn process_section_header(&mut self, name: &str, span: Span) {
         let new_section: AssemblerSection =
             AssemblerSection::new(name, self.current_instruction * 4);
 
         if new_section == AssemblerSection::Unknown {
             self.errors.push(AssemblerError::UnknownSectionHeader {
                 header: name.to_owned(),
+                span,
             });
             return;
         }
@@ -277,18 +478,163 @@ impl Assembler {
         if self.phase != AssemblerPhase::First {
             return;
         }
-        if let Some(_) = i.get_string_constant() {
-            match i.label_name() {
-                Some(name) => self.symbols.set_symbol_offset(&name, self.ro_offset),
-                None => {
-                    println!("Found string const without associated label");
-                    return;
-                }
+        let s = match i.arguments.as_slice() {
+            [(Token::IRString { name }, _)] => name,
+            _ => {
+                self.invalid_argument(i);
+                return;
             }
-        } else {
-            println!("String constant following an .asciiz was empty");
+        };
+        match i.label_name() {
+            Some(name) => self.symbols.set_symbol_offset(name, self.ro_offset),
+            None => {
+                self.errors
+                    .push(AssemblerError::StringConstantDeclaredWithoutLabel {
+                        line: i.span.line(&self.code),
+                        span: i.span,
+                    });
+                return;
+            }
+        }
+        self.ro.extend(s.as_bytes());
+        self.ro.push(0);
+        self.ro_offset += s.len() as u32 + 1;
+    }
+
+    /// `.ascii "text"`: the string's bytes, without a terminating zero
+    fn handle_ascii(&mut self, i: &AssemblerInstruction) {
+        match i.arguments.as_slice() {
+            [(Token::IRString { name }, _)] => {
+                self.place_label(i);
+                self.emit_ro(name.as_bytes());
+            }
+            _ => self.invalid_argument(i),
+        }
+    }
+
+    /// `.byte`, `.half` and `.word`: each argument as a big-endian value `width` bytes
+    /// wide. Labels are filled in by the second phase, once every label has an offset.
+    fn handle_data(&mut self, i: &AssemblerInstruction, width: u8) {
+        if i.arguments.is_empty() {
+            self.invalid_argument(i);
             return;
         }
+        self.place_label(i);
+        for (token, span) in &i.arguments {
+            let value = match token {
+                Token::IntegerOperand { value, sign_bit } => {
+                    if *sign_bit {
+                        -(*value as i64)
+                    } else {
+                        *value as i64
+                    }
+                }
+                Token::LabelUsage { name } => {
+                    self.ro_fixups.push(DataFixup {
+                        offset: self.ro.len(),
+                        width,
+                        name: name.to_owned(),
+                        span: *span,
+                    });
+                    0
+                }
+                _ => {
+                    self.errors.push(AssemblerError::InvalidDirectiveArgument {
+                        directive: i.directive_name().unwrap_or_default().to_owned(),
+                        span: *span,
+                    });
+                    continue;
+                }
+            };
+            match encode_data(value, width, *span) {
+                Ok(bytes) => self.emit_ro(&bytes),
+                Err(e) => self.errors.push(e),
+            }
+        }
+    }
+
+    /// `.space n`: n zero bytes
+    fn handle_space(&mut self, i: &AssemblerInstruction) {
+        if let Some(count) = self.count_argument(i) {
+            self.place_label(i);
+            self.emit_ro(&vec![0; count as usize]);
+        }
+    }
+
+    /// `.align n`: zero bytes up to the next multiple of n. A label on the line points
+    /// past the padding.
+    fn handle_align(&mut self, i: &AssemblerInstruction) {
+        match self.count_argument(i) {
+            Some(0) => self.invalid_argument(i),
+            Some(alignment) => {
+                let alignment = alignment as u32;
+                let padding = (alignment - self.ro_offset % alignment) % alignment;
+                self.emit_ro(&vec![0; padding as usize]);
+                self.place_label(i);
+            }
+            None => {}
+        }
+    }
+
+    /// The single non-negative integer argument of `.space` and `.align`, at most 65535
+    fn count_argument(&mut self, i: &AssemblerInstruction) -> Option<u16> {
+        match i.arguments.as_slice() {
+            [(
+                Token::IntegerOperand {
+                    value,
+                    sign_bit: false,
+                },
+                _,
+            )] if *value <= u16::MAX as u32 => Some(*value as u16),
+            _ => {
+                self.invalid_argument(i);
+                None
+            }
+        }
+    }
+
+    fn invalid_argument(&mut self, i: &AssemblerInstruction) {
+        self.errors.push(AssemblerError::InvalidDirectiveArgument {
+            directive: i.directive_name().unwrap_or_default().to_owned(),
+            span: i.arguments.first().map_or(i.span, |(_, span)| *span),
+        });
+    }
+
+    /// Points the instruction's label, if it has one, at the next byte of read-only data
+    fn place_label(&mut self, i: &AssemblerInstruction) {
+        if let Some(name) = i.label_name() {
+            self.symbols.set_symbol_offset(name, self.ro_offset);
+        }
+    }
+
+    fn emit_ro(&mut self, bytes: &[u8]) {
+        self.ro.extend(bytes);
+        self.ro_offset += bytes.len() as u32;
+    }
+}
+
+/// A label used as a value in `.byte`, `.half` or `.word`, written into the read-only
+/// data once every label has an offset
+#[derive(Debug)]
+struct DataFixup {
+    offset: usize,
+    width: u8,
+    name: String,
+    span: Span,
+}
+
+/// `value` as `width` big-endian bytes, if it fits either signed or unsigned
+fn encode_data(value: i64, width: u8, span: Span) -> Result<Vec<u8>, AssemblerError> {
+    let bits = 8 * width as u32;
+    if value < -(1 << (bits - 1)) || value >= 1 << bits {
+        return Err(AssemblerError::DataValueOutOfRange { value, width, span });
+    }
+    Ok(value.to_be_bytes()[8 - width as usize..].to_vec())
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -330,9 +676,12 @@ impl Symbol {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum SymbolType {
+    /// A label in the code section, whose offset is a byte offset into the code
     Label,
+    /// A label in the data section, whose offset is into the read-only data
+    Data,
 }
 
 #[derive(Debug)]
@@ -353,6 +702,13 @@ impl SymbolTable {
         self.symbols.iter().any(|s| s.name == name)
     }
 
+    pub fn symbol_type(&self, name: &str) -> Option<SymbolType> {
+        self.symbols
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.symbol_type)
+    }
+
     pub fn get_symbol_offset(&self, name: &str) -> Option<u32> {
         for symbol in &self.symbols {
             if symbol.name == name {
@@ -362,6 +718,15 @@ impl SymbolTable {
         None
     }
 
+    /// The code label closest at or before `offset`, with the distance past it.
+    pub fn label_for_offset(&self, offset: u32) -> Option<(&str, u32)> {
+        self.symbols
+            .iter()
+            .filter(|s| s.symbol_type == SymbolType::Label)
+            .filter_map(|s| Some((s.name.as_str(), offset.checked_sub(s.offset?)?)))
+            .min_by_key(|(_, distance)| *distance)
+    }
+
     pub fn set_symbol_offset(&mut self, name: &str, offset: u32) {
         for symbol in &mut self.symbols {
             if symbol.name == name {
@@ -371,10 +736,19 @@ impl SymbolTable {
     }
 }
 
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Tests for mod
 #[cfg(test)]
 mod tests {
-    use crate::vm::VM;
+    use crate::{
+        opcode::OpCode,
+        vm::{RunOutcome, VM},
+    };
 
     use super::*;
 
@@ -385,11 +759,25 @@ mod tests {
         sym.add_symbol(new_symbol);
         assert_eq!(sym.symbols.len(), 1);
         let v = sym.get_symbol_offset("test");
-        assert_eq!(true, v.is_some());
+        assert!(v.is_some());
         let v = v.unwrap();
         assert_eq!(v, 12);
         let v = sym.get_symbol_offset("does_not_exist");
-        assert_eq!(v.is_some(), false);
+        assert!(v.is_none());
+    }
+
+    #[test]
+    fn test_label_for_offset() {
+        let mut sym = SymbolTable::new();
+        sym.add_symbol(Symbol::new("start", SymbolType::Label, 0));
+        sym.add_symbol(Symbol::new("loop", SymbolType::Label, 8));
+        sym.add_symbol(Symbol::new("message", SymbolType::Data, 10));
+        assert_eq!(sym.label_for_offset(0), Some(("start", 0)));
+        assert_eq!(sym.label_for_offset(4), Some(("start", 4)));
+        assert_eq!(sym.label_for_offset(12), Some(("loop", 4)));
+        sym = SymbolTable::new();
+        sym.add_symbol(Symbol::new("late", SymbolType::Label, 8));
+        assert_eq!(sym.label_for_offset(4), None);
     }
 
     #[test]
@@ -398,12 +786,12 @@ mod tests {
         let test_string = r".data
 .code
     load $0 #100
-    load $1 #1
+    load $1 @test
     load $2 #0
 test:
     inc $0
     neq $0 $2
-    jeq @test
+    jeq $1
     hlt";
         if let Err(e) = asm.assemble(test_string) {
             return Err(e.clone());
@@ -414,4 +802,556 @@ test:
         assert_eq!(vm.program_len(), 28);
         Ok(())
     }
+
+    #[test]
+    fn test_assemble_with_comments() -> Result<(), Vec<AssemblerError>> {
+        let mut plain = Assembler::new();
+        let plain = plain
+            .assemble(".data\nhello: .asciiz \"a;b\"\n.code\nload $0 #1\nhlt\n")
+            .map_err(|e| e.clone())?
+            .clone();
+
+        let mut asm = Assembler::new();
+        let test_string = r#"; Greeting
+.data
+hello: .asciiz "a;b" // not a comment inside the string
+.code
+    load $0 #1 ; one
+
+    // done
+    hlt"#;
+        assert_eq!(asm.assemble(test_string).map_err(|e| e.clone())?, &plain);
+        assert_eq!(asm.ro, b"a;b\0");
+        assert_eq!(asm.program.comments.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_label_names() -> Result<(), Vec<AssemblerError>> {
+        let mut asm = Assembler::new();
+        let test_string = r#".data
+msg_1: .asciiz "hi"
+.code
+    load $0 #3
+    load $1 @Loop
+    load $2 @done.L2
+Loop:
+    dec $0
+    eq $0 $3
+    jeq $2
+    jmp $1
+done.L2:
+    hlt"#;
+        if let Err(e) = asm.assemble(test_string) {
+            return Err(e.clone());
+        }
+        assert_eq!(asm.symbols.get_symbol_offset("msg_1"), Some(0));
+        assert_eq!(asm.symbols.get_symbol_offset("Loop"), Some(12));
+        assert_eq!(asm.symbols.get_symbol_offset("loop"), None);
+        assert_eq!(asm.symbols.get_symbol_offset("done.L2"), Some(28));
+
+        let mut vm = VM::new();
+        vm.add_program(&mut asm.bytecode);
+        assert_eq!(vm.run(), RunOutcome::Halted);
+        assert_eq!(vm.read_registers()[0], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_asciiz() -> Result<(), Vec<AssemblerError>> {
+        let mut asm = Assembler::new();
+        let test_string = r#".data
+hello: .asciiz "Hello"
+world: .asciiz "World!"
+.code
+    prts @world
+    prts @hello
+    hlt"#;
+        if let Err(e) = asm.assemble(test_string) {
+            return Err(e.clone());
+        }
+        assert_eq!(asm.ro, b"Hello\0World!\0".to_vec());
+        assert_eq!(asm.symbols.get_symbol_offset("hello"), Some(0));
+        assert_eq!(asm.symbols.get_symbol_offset("world"), Some(6));
+        assert_eq!(&asm.bytecode[0..4], &[OpCode::PRTS as u8, 0, 6, 0]);
+
+        let mut vm = VM::new();
+        vm.set_ro_data(asm.ro.clone());
+        assert_eq!(vm.read_ro_string(6), Ok("World!"));
+        vm.add_program(&mut asm.bytecode);
+        assert_eq!(vm.run(), RunOutcome::Halted);
+        assert_eq!(*vm.read_pc(), 12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_asciiz_errors() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\n.asciiz \"a\"\nx: .asciiz #5\n.code\nhlt\n";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![
+                AssemblerError::StringConstantDeclaredWithoutLabel {
+                    line: 2,
+                    span: Span::new(6, 17)
+                },
+                AssemblerError::InvalidDirectiveArgument {
+                    directive: "asciiz".to_string(),
+                    span: Span::new(29, 31)
+                },
+            ]
+        );
+        assert!(asm.ro.is_empty());
+    }
+
+    #[test]
+    fn test_assemble_data_directives() -> Result<(), Vec<AssemblerError>> {
+        let mut asm = Assembler::new();
+        let test_string = r#".data
+table: .byte #1, -#1, 0xFF
+aligned: .align #4
+words: .word #258, @main, @table
+half: .half -#2
+gap: .space #3
+text: .ascii "ab"
+end: .byte #0
+full: .word 0x12345678, 0xFFFFFFFF, -0x80000000
+.code
+    hlt
+main: load $0 @words
+    hlt"#;
+        if let Err(e) = asm.assemble(test_string) {
+            return Err(e.clone());
+        }
+        assert_eq!(
+            asm.ro,
+            vec![
+                1, 0xFF, 0xFF, 0, //
+                0, 0, 1, 2, //
+                0, 0, 0, 4, //
+                0, 0, 0, 0, //
+                0xFF, 0xFE, //
+                0, 0, 0, //
+                b'a', b'b', //
+                0, //
+                0x12, 0x34, 0x56, 0x78, //
+                0xFF, 0xFF, 0xFF, 0xFF, //
+                0x80, 0, 0, 0
+            ]
+        );
+        let offsets: Vec<Option<u32>> =
+            ["table", "aligned", "words", "half", "gap", "text", "end", "full"]
+                .iter()
+                .map(|name| asm.symbols.get_symbol_offset(name))
+                .collect();
+        assert_eq!(
+            offsets,
+            vec![Some(0), Some(4), Some(4), Some(16), Some(18), Some(21), Some(23), Some(24)]
+        );
+        assert_eq!(&asm.bytecode[4..8], &[OpCode::LOAD as u8, 0, 0, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_data_directive_errors() {
+        let mut asm = Assembler::new();
+        let test_string = r#".data
+a: .byte #256, @nowhere
+b: .space @a
+c: .align #0
+d: .word #1, "x"
+.code
+hlt
+"#;
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![
+                AssemblerError::DataValueOutOfRange {
+                    value: 256,
+                    width: 1,
+                    span: Span::new(15, 19)
+                },
+                AssemblerError::InvalidDirectiveArgument {
+                    directive: "space".to_string(),
+                    span: Span::new(40, 42)
+                },
+                AssemblerError::InvalidDirectiveArgument {
+                    directive: "align".to_string(),
+                    span: Span::new(53, 55)
+                },
+                AssemblerError::InvalidDirectiveArgument {
+                    directive: "word".to_string(),
+                    span: Span::new(69, 72)
+                },
+            ]
+        );
+        assert_eq!(errors[0].to_string(), "Value 256 doesn't fit in 1 byte");
+
+        // Labels are only resolved once the first phase has succeeded
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".data\nx: .half @nowhere\n.code\nhlt\n").unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![AssemblerError::UndefinedLabel {
+                name: "nowhere".to_string(),
+                span: Span::new(15, 23)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_assemble_executable() -> Result<(), Vec<AssemblerError>> {
+        let mut asm = Assembler::new();
+        let test_string = r#".data
+hello: .asciiz "Hello"
+.code
+helper:
+    inc $1
+    ret
+main:
+    call @helper
+    prts @hello
+    hlt"#;
+        if let Err(e) = asm.assemble(test_string) {
+            return Err(e.clone());
+        }
+        let exe = asm.to_executable();
+        assert_eq!(exe.entry, 8);
+        assert_eq!(exe.code, asm.bytecode);
+        assert_eq!(exe.ro, b"Hello\0".to_vec());
+
+        let mut vm = VM::new();
+        vm.load_executable(&exe.to_bytes()).unwrap();
+        assert_eq!(*vm.read_pc(), 8);
+        assert_eq!(vm.run(), RunOutcome::Halted);
+        assert_eq!(vm.read_registers()[1], 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_shifts() -> Result<(), Vec<AssemblerError>> {
+        let mut asm = Assembler::new();
+        let test_string = r".data
+.code
+    load $0 0x00F0
+    shl $0 #4 $1
+    shr $1 $0 $2
+    xor $1 $0 $3
+    not $3 $4
+    hlt";
+        if let Err(e) = asm.assemble(test_string) {
+            return Err(e.clone());
+        }
+        assert_eq!(&asm.bytecode[4..8], &[OpCode::SHL as u8, 0, 0x84, 1]);
+        assert_eq!(&asm.bytecode[8..12], &[OpCode::SHR as u8, 1, 0, 2]);
+
+        let mut vm = VM::new();
+        vm.add_program(&mut asm.bytecode);
+        assert_eq!(vm.run(), RunOutcome::Halted);
+        assert_eq!(vm.read_registers()[1], 0x0F00);
+        assert_eq!(vm.read_registers()[2], 0);
+        assert_eq!(vm.read_registers()[3], 0x0FF0);
+        assert_eq!(vm.read_registers()[4], !0x0FF0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_shift_out_of_range() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\n.code\n    shl $0 #128 $1\n";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![AssemblerError::InvalidShiftAmount {
+                amount: 128,
+                span: Span::new(23, 27)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_assemble_heap_access() -> Result<(), Vec<AssemblerError>> {
+        let mut asm = Assembler::new();
+        let test_string = r".data
+.code
+    load $0 #8
+    aloc $0
+    load $1 #2
+    load $2 #300
+    setm $1 $2 #2
+    loadm $3 $1 #1
+    loadm $4 $1
+    hlt";
+        if let Err(e) = asm.assemble(test_string) {
+            return Err(e.clone());
+        }
+        assert_eq!(&asm.bytecode[16..20], &[OpCode::SETM as u8, 1, 2, 2]);
+        assert_eq!(&asm.bytecode[24..28], &[OpCode::LOADM as u8, 4, 1, 0]);
+
+        let mut vm = VM::new();
+        vm.add_program(&mut asm.bytecode);
+        assert_eq!(vm.run(), RunOutcome::Halted);
+        assert_eq!(vm.read_heap(), &[0, 0, 0x01, 0x2C, 0, 0, 0, 0]);
+        assert_eq!(vm.read_registers()[3], 0x01);
+        assert_eq!(vm.read_registers()[4], 0x012C_0000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let mut asm = Assembler::new();
+        let source = "\n\nstart: hlt\n.data\n.code\n";
+        let errors = asm.assemble(source).unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![AssemblerError::NoSegmentDeclarationFound {
+                line: 3,
+                span: Span::new(2, 7)
+            }]
+        );
+
+        let mut asm = Assembler::new();
+        let source = ".data\n.code\n    inc $0\n    inc $0 junk\n";
+        let errors = asm.assemble(source).unwrap_err().clone();
+        assert_eq!(errors[0].span(), Span::new(27, 38));
+        assert_eq!(
+            errors[0].render("test.lr", source),
+            "error: Parse error: expected an instruction or directive\n \
+             --> test.lr:4:5\n  \
+             |\n\
+             4 |     inc $0 junk\n  \
+             |     ^^^^^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn test_assemble_invalid_memory_width() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\n.code\n    loadm $0 $1 #3\n";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![AssemblerError::InvalidMemoryWidth {
+                width: 3,
+                span: Span::new(28, 30)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_assemble_bad_operands() {
+        let mut asm = Assembler::new();
+        let test_string = ".data\n.code\n    call @nowhere\n    load $0 -#40000\n    hlt\n";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![
+                AssemblerError::UndefinedLabel {
+                    name: "nowhere".to_string(),
+                    span: Span::new(21, 29)
+                },
+                AssemblerError::InvalidOperand {
+                    operand: "-#40000".to_string(),
+                    span: Span::new(42, 49)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_immediate_range() {
+        let mut asm = Assembler::new();
+        let bytecode = asm.assemble(".data\n.code\nload $0 -#32768\nload $1 #65535\n").unwrap();
+        assert_eq!(
+            bytecode,
+            &vec![
+                OpCode::LOAD as u8, 0, 0x80, 0x00, //
+                OpCode::LOAD as u8, 1, 0xFF, 0xFF
+            ]
+        );
+
+        let mut asm = Assembler::new();
+        let errors = asm.assemble(".data\n.code\nload $0 -#32769\nload $1 #70000\n").unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![
+                AssemblerError::InvalidOperand {
+                    operand: "-#32769".to_string(),
+                    span: Span::new(20, 27)
+                },
+                AssemblerError::InvalidOperand {
+                    operand: "#70000".to_string(),
+                    span: Span::new(36, 42)
+                },
+            ]
+        );
+        assert_eq!(errors[0].to_string(), "Invalid operand (-#32769)");
+    }
+
+    #[test]
+    fn test_assemble_label_out_of_range() {
+        let mut asm = Assembler::new();
+        let test_string = ".data
+.space #65535
+.space #1
+far: .byte #0
+.code
+load $0 @far
+";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![AssemblerError::LabelOutOfRange {
+                name: "far".to_string(),
+                offset: 65536,
+                span: Span::new(58, 62)
+            }]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "Label far is at 65536, past the 16-bit operand limit of 65535"
+        );
+    }
+
+    #[test]
+    fn test_assemble_operand_signatures() {
+        let mut asm = Assembler::new();
+        let test_string = r".data
+.code
+    add $0 #5
+    load $0 $1
+    loadm $0
+    loadm $0 $1 #2
+";
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![
+                AssemblerError::WrongOperandCount {
+                    opcode: OpCode::ADD,
+                    expected: 3..=3,
+                    found: 2,
+                    span: Span::new(16, 25)
+                },
+                AssemblerError::WrongOperandKind {
+                    opcode: OpCode::LOAD,
+                    expected: vec![
+                        OperandKind::Integer,
+                        OperandKind::Label,
+                        OperandKind::DataLabel
+                    ],
+                    found: Some(OperandKind::Register),
+                    span: Span::new(38, 40)
+                },
+                AssemblerError::WrongOperandCount {
+                    opcode: OpCode::LOADM,
+                    expected: 2..=3,
+                    found: 1,
+                    span: Span::new(45, 53)
+                },
+            ]
+        );
+        assert_eq!(errors[0].to_string(), "add takes 3 operands, found 2");
+        assert_eq!(
+            errors[1].to_string(),
+            "Expected integer, code label or data label operand for load, found register"
+        );
+        assert_eq!(errors[2].to_string(), "loadm takes 2 to 3 operands, found 1");
+    }
+
+    #[test]
+    fn test_assemble_operand_checks() {
+        let mut asm = Assembler::new();
+        let test_string = r#".data
+msg: .asciiz "hi"
+.code
+    lui $0 #5
+    inc $200
+    call @msg
+"#;
+        let errors = asm.assemble(test_string).unwrap_err();
+        assert_eq!(
+            errors,
+            &vec![
+                AssemblerError::UnimplementedOpcode {
+                    opcode: OpCode::LUI,
+                    span: Span::new(34, 43)
+                },
+                AssemblerError::InvalidRegister {
+                    register: 200,
+                    span: Span::new(52, 56)
+                },
+                AssemblerError::WrongOperandKind {
+                    opcode: OpCode::CALL,
+                    expected: vec![OperandKind::Integer, OperandKind::Label],
+                    found: Some(OperandKind::DataLabel),
+                    span: Span::new(66, 70)
+                },
+            ]
+        );
+        assert_eq!(errors[0].to_string(), "lui is reserved and not implemented by the VM");
+        assert_eq!(errors[1].to_string(), "No register 200 (registers go from 0 to 31)");
+        assert_eq!(
+            errors[2].to_string(),
+            "Expected integer or code label operand for call, found data label"
+        );
+    }
+
+    #[test]
+    fn test_assemble_call() -> Result<(), Vec<AssemblerError>> {
+        let mut asm = Assembler::new();
+        let test_string = r".data
+.code
+    load $0 #5
+    call @double
+    push $0
+    hlt
+double:
+    add $0 $0 $0
+    ret";
+        if let Err(e) = asm.assemble(test_string) {
+            return Err(e.clone());
+        }
+        assert_eq!(&asm.bytecode[4..8], &[OpCode::CALL as u8, 0, 16, 0]);
+
+        let mut vm = VM::new();
+        vm.add_program(&mut asm.bytecode);
+        assert_eq!(vm.run(), RunOutcome::Halted);
+        assert_eq!(vm.read_registers()[0], 10);
+        assert_eq!(vm.read_stack(), &[10]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_f64_program() -> Result<(), Vec<AssemblerError>> {
+        let mut asm = Assembler::new();
+        let test_string = r".data
+.code
+    loadf64 $f0 #1.5
+    loadf64 $f1 #2
+    load $0 @done
+    addf64 $f0 $f1 $f2
+    gtf64 $f2 $f1
+    jeq $0
+    load $1 #1
+done:
+    hlt";
+        if let Err(e) = asm.assemble(test_string) {
+            return Err(e.clone());
+        }
+        // Two 12-byte LOADF64s followed by six 4-byte instructions
+        assert_eq!(asm.bytecode.len(), 48);
+        assert_eq!(asm.symbols.get_symbol_offset("done"), Some(44));
+        assert_eq!(&asm.bytecode[4..12], &1.5f64.to_be_bytes());
+        assert_eq!(&asm.bytecode[16..24], &2.0f64.to_be_bytes());
+
+        let mut vm = VM::new();
+        vm.add_program(&mut asm.bytecode);
+        assert_eq!(vm.run(), RunOutcome::Halted);
+        assert_eq!(vm.read_float_registers()[2], 3.5);
+        assert_eq!(vm.read_registers()[1], 0);
+        Ok(())
+    }
 }
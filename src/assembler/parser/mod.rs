@@ -3,8 +3,17 @@ pub mod parsers;
 mod token;
 pub use token::*;
 
-use super::Program;
+use super::{span::Span, Program};
 
-pub fn parse_program(s: &str) -> Result<Program, nom::Err<()>> {
-    Ok(super::parser::parsers::program(s)?.1)
+/// Parses a whole source file. On failure, returns the span of the first line that
+/// isn't a valid instruction or directive.
+pub fn parse_program(s: &str) -> Result<Program, Span> {
+    let rem = match parsers::program(s) {
+        Ok((rem, program)) if rem.trim().is_empty() => return Ok(program),
+        Ok((rem, _)) => rem,
+        Err(_) => s,
+    };
+    let start = s.len() - rem.trim_start().len();
+    let line = s[start..].lines().next().unwrap_or("");
+    Err(Span::new(start, start + line.trim_end().len()))
 }
@@ -1,75 +1,170 @@
 use super::Token;
-use crate::assembler::{Program, instruction::AssemblerInstruction};
+use crate::assembler::{instruction::AssemblerInstruction, span::Span, Comment, Program};
 use crate::opcode::OpCode;
 
 use nom::bytes::complete::take_until;
-use nom::character::complete::{space1, newline, space0};
+use nom::character::complete::{space1, newline, not_line_ending, space0};
 use nom::combinator::eof;
 use nom::sequence::{preceded, delimited};
 use nom::{
     branch::alt,
     bytes::complete::tag,
     character::complete::one_of,
-    character::complete::{alpha1, char, digit1},
-    combinator::{map_res, recognize, opt},
-    multi::{many0, many1},
-    sequence::{terminated, tuple},
+    character::complete::{alpha1, alphanumeric1, char, digit1},
+    combinator::{map, map_res, opt, recognize},
+    multi::{many0, many1, separated_list1},
+    sequence::{pair, terminated, tuple},
     IResult,
 };
 
+/// Runs `parser`, pairing its output with the span it consumed within `base`.
+/// `base` must be the input the enclosing parser was given.
+fn spanned<'a, O>(
+    base: &'a str,
+    mut parser: impl FnMut(&'a str) -> IResult<&'a str, O, ()>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, (O, Span), ()> {
+    move |s| {
+        let (rem, out) = parser(s)?;
+        Ok((
+            rem,
+            (out, Span::new(base.len() - s.len(), base.len() - rem.len())),
+        ))
+    }
+}
+
+/// A `;` or `//` comment, running to the end of the line
+pub fn comment(s: &str) -> IResult<&str, &str, ()> {
+    match recognize(preceded(alt((tag(";"), tag("//"))), not_line_ending))(s) {
+        Ok((rem, text)) => Ok((rem, text.trim_end())),
+        Err(e) => Err(e),
+    }
+}
+
+/// `comment` along with where it is within `base`
+fn spanned_comment<'a>(base: &'a str) -> impl FnMut(&'a str) -> IResult<&'a str, Comment, ()> {
+    map(spanned(base, comment), |(text, span)| Comment {
+        text: text.to_string(),
+        span: Span::new(span.start, span.start + text.len()),
+    })
+}
+
+/// The end of a line, with any trailing comment
+fn line_end<'a>(base: &'a str) -> impl FnMut(&'a str) -> IResult<&'a str, Option<Comment>, ()> {
+    preceded(
+        space0,
+        terminated(
+            opt(spanned_comment(base)),
+            alt((newline, map(eof, |_| '\n'))),
+        ),
+    )
+}
+
+/// Lines that are empty or hold only whitespace and comments
+fn blank_lines<'a>(base: &'a str) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<Comment>, ()> {
+    map(
+        many0(delimited(space0, opt(spanned_comment(base)), newline)),
+        |comments| comments.into_iter().flatten().collect(),
+    )
+}
+
+/// Parses instructions and directives, one per line. Spans are relative to `s`.
 pub fn program(s: &str) -> IResult<&str, Program, ()> {
-    match many1(delimited(space0, alt((instruction, directive)), space0))(s)
+    match tuple((
+        many1(pair(
+            blank_lines(s),
+            delimited(space0, spanned(s, alt((instruction, directive))), space0),
+        )),
+        blank_lines(s),
+        // The last line may be a comment without a newline
+        opt(preceded(space0, spanned_comment(s))),
+    ))(s)
     {
-        Ok((rem, instructions)) => Ok((rem, Program { instructions })),
+        Ok((rem, (lines, trailing, last))) => {
+            let mut instructions = vec![];
+            let mut comments = vec![];
+            for (blank, (instruction, span)) in lines {
+                comments.extend(blank);
+                instructions.push(instruction.shift(span.start));
+            }
+            comments.extend(trailing.into_iter().chain(last));
+            Ok((
+                rem,
+                Program {
+                    instructions,
+                    comments,
+                },
+            ))
+        }
         Err(e) => Err(e),
     }
 }
 
+/// A label declaration, which may end its line (and carry a comment) before what it labels
+fn label<'a>(
+    base: &'a str,
+) -> impl FnMut(&'a str) -> IResult<&'a str, (Token, Option<Comment>), ()> {
+    map(
+        pair(
+            label_declaration,
+            preceded(
+                space0,
+                opt(terminated(opt(spanned_comment(base)), newline)),
+            ),
+        ),
+        |(label, comment)| (label, comment.flatten()),
+    )
+}
+
 pub fn instruction(s: &str) -> IResult<&str, AssemblerInstruction, ()> {
-    match terminated(
-        tuple((
-            opt(label_declaration),
-            space0,
-            opcode,
-            opt(preceded(space1, operand)),
-            opt(preceded(space1, operand)),
-            opt(preceded(space1, operand)),
-        )),
-        newline
-    )(s).or(terminated(
-        tuple((
-            opt(label_declaration),
-            space0,
-            opcode,
-            opt(preceded(space1, operand)),
-            opt(preceded(space1, operand)),
-            opt(preceded(space1, operand)),
-        )),
-        eof
-    )(s))
+    match pair(
+        spanned(
+            s,
+            tuple((
+                opt(label(s)),
+                space0,
+                opcode,
+                opt(preceded(space1, spanned(s, operand))),
+                opt(preceded(space1, spanned(s, operand))),
+                opt(preceded(space1, spanned(s, operand))),
+            )),
+        ),
+        line_end(s),
+    )(s)
     {
-        Ok((rem, (label_dec, _, opcode, operand1, operand2, operand3))) => Ok((
-            rem,
-            AssemblerInstruction::new(
+        Ok((rem, (((label, _, opcode, operand1, operand2, operand3), span), comment))) => {
+            let (label_dec, label_comment) = label.unzip();
+            let mut instruction = AssemblerInstruction::new(
                 Some(opcode),
-                [operand1, operand2, operand3],
+                [None, None, None],
                 label_dec,
                 None,
-            ),
-        )),
+            );
+            instruction.set_operands([operand1, operand2, operand3]);
+            instruction.span = span;
+            instruction.comments = label_comment.flatten().into_iter().chain(comment).collect();
+            Ok((rem, instruction))
+        }
         Err(e) => Err(e),
     }
 }
 
 pub fn operand(s: &str) -> IResult<&str, Token, ()> {
-    match alt((register, integer_operand, label_usage, irstring))(s) {
+    match alt((
+        float_register,
+        register,
+        float_operand,
+        integer_operand,
+        label_usage,
+        irstring,
+    ))(s)
+    {
         Ok((rem, token)) => Ok((rem, token)),
         Err(e) => Err(e),
     }
 }
 
 pub fn opcode(s: &str) -> IResult<&str, Token, ()> {
-    match alpha1(s) {
+    match alphanumeric1(s) {
         Ok((rem, opcode)) => Ok((
             rem,
             Token::Op {
@@ -88,7 +183,43 @@ pub fn register(s: &str) -> IResult<&str, Token, ()> {
         Ok((rem, (_, number))) => Ok((
             rem,
             Token::Register {
-                id: number.parse().unwrap(),
+                id: number.parse().map_err(|_| nom::Err::Error(()))?,
+            },
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn float_register(s: &str) -> IResult<&str, Token, ()> {
+    match tuple((char('$'), one_of("fF"), digit1))(s) {
+        Ok((rem, (_, _, number))) => Ok((
+            rem,
+            Token::FloatRegister {
+                id: number.parse().map_err(|_| nom::Err::Error(()))?,
+            },
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn float_operand(mut s: &str) -> IResult<&str, Token, ()> {
+    let mut sign = 1.0;
+    if s.starts_with('-') {
+        sign = -1.0;
+        s = &s[1..];
+    }
+    match map_res(
+        preceded(
+            tag("#"),
+            recognize(tuple((digit1, char('.'), digit1))),
+        ),
+        |out: &str| out.parse::<f64>(),
+    )(s)
+    {
+        Ok((rem, value)) => Ok((
+            rem,
+            Token::FloatOperand {
+                value: sign * value,
             },
         )),
         Err(e) => Err(e),
@@ -116,7 +247,7 @@ pub fn integer_operand(mut s: &str) -> IResult<&str, Token, ()> {
             )),
         )),
         |(tag, out)| {
-            u16::from_str_radix(
+            u32::from_str_radix(
                 &str::replace(out, "_", ""),
                 if tag.to_lowercase() == "0x" { 16 } else { 10 },
             )
@@ -128,9 +259,18 @@ pub fn integer_operand(mut s: &str) -> IResult<&str, Token, ()> {
     }
 }
 
+/// A label or directive name: letters, digits, `_` and `.`, not starting with a digit.
+/// Names are case-sensitive.
+pub fn identifier(s: &str) -> IResult<&str, &str, ()> {
+    recognize(pair(
+        alt((alpha1, tag("_"), tag("."))),
+        many0(alt((alphanumeric1, tag("_"), tag(".")))),
+    ))(s)
+}
+
 pub fn label_declaration(s: &str) -> IResult<&str, Token, ()> {
-    match tuple((alpha1, char(':'), space0, opt(newline)))(s) {
-        Ok((rem, (name, _, _, _))) => Ok((
+    match terminated(identifier, char(':'))(s) {
+        Ok((rem, name)) => Ok((
             rem,
             Token::LabelDeclaration {
                 name: name.to_string(),
@@ -142,11 +282,11 @@ pub fn label_declaration(s: &str) -> IResult<&str, Token, ()> {
 
 pub fn label_usage(s: &str) -> IResult<&str, Token, ()> {
     // Parsing a label usage.
-    match tuple((char('@'), alpha1))(s) {
+    match tuple((char('@'), identifier))(s) {
         Ok((rem, (_, name))) => Ok((
             rem,
             Token::LabelUsage {
-                name: name.to_lowercase(),
+                name: name.to_string(),
             },
         )),
         Err(e) => Err(e),
@@ -154,35 +294,41 @@ pub fn label_usage(s: &str) -> IResult<&str, Token, ()> {
 }
 
 pub fn directive(s: &str) -> IResult<&str, AssemblerInstruction, ()> {
-    match terminated(tuple((
-        opt(label_declaration),
-        space0,
-        char('.'),
-        alpha1,
-        opt(preceded(space1, operand)),
-        opt(preceded(space1, operand)),
-        opt(preceded(space1, operand)),
-        space0
-    )), newline)(s).or(terminated(tuple((
-        opt(label_declaration),
-        space0,
-        char('.'),
-        alpha1,
-        opt(preceded(space1, operand)),
-        opt(preceded(space1, operand)),
-        opt(preceded(space1, operand)),
-        space0
-    )), eof)(s))
+    match pair(
+        spanned(
+            s,
+            tuple((
+                opt(label(s)),
+                space0,
+                char('.'),
+                identifier,
+                opt(preceded(
+                    space1,
+                    separated_list1(
+                        tuple((space0, char(','), space0)),
+                        spanned(s, operand),
+                    ),
+                )),
+            )),
+        ),
+        line_end(s),
+    )(s)
     {
-        Ok((rem, (label, _, _, directive, operand1, operand2, operand3, _))) => Ok((
-            rem,
-            AssemblerInstruction {
-                opcode: None,
-                operands: [operand1, operand2, operand3],
-                directive: Some(Token::Directive { name: directive.to_lowercase() }),
+        Ok((rem, (((label, _, _, directive, arguments), span), comment))) => {
+            let (label, label_comment) = label.unzip();
+            let mut instruction = AssemblerInstruction::new(
+                None,
+                [None, None, None],
                 label,
-            },
-        )),
+                Some(Token::Directive {
+                    name: directive.to_lowercase(),
+                }),
+            );
+            instruction.arguments = arguments.unwrap_or_default();
+            instruction.span = span;
+            instruction.comments = label_comment.flatten().into_iter().chain(comment).collect();
+            Ok((rem, instruction))
+        }
         Err(e) => Err(e),
     }
 }
@@ -210,18 +356,23 @@ mod tests {
     #[test]
     fn test_parse_register() {
         let result = register("$0 ");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let result = register("0 ");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
         let result = register("$a ");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+
+        // Ids too big for a byte are a parse error rather than a panic
+        let result = register("$300 ");
+        assert!(result.is_err());
+        assert!(instruction("inc $300\n").is_err());
     }
 
     #[test]
     fn test_parse_integer_operand() {
         // Test a valid integer operand
         let result = integer_operand("#10 ");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, tok) = result.unwrap();
         assert_eq!(rest, " ");
         assert_eq!(
@@ -234,32 +385,68 @@ mod tests {
 
         // Test an invalid one (missing the #)
         let result = integer_operand("10 ");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_float_register() {
+        let result = float_register("$f12 ");
+        assert_eq!(result, Ok((" ", Token::FloatRegister { id: 12 })));
+        let result = float_register("$12 ");
+        assert!(result.is_err());
+        let result = operand("$f3");
+        assert_eq!(result, Ok(("", Token::FloatRegister { id: 3 })));
+    }
+
+    #[test]
+    fn test_parse_float_operand() {
+        let result = float_operand("#2.75 ");
+        assert_eq!(result, Ok((" ", Token::FloatOperand { value: 2.75 })));
+        let result = float_operand("-#0.5");
+        assert_eq!(result, Ok(("", Token::FloatOperand { value: -0.5 })));
+
+        // Integers are left to integer_operand
+        let result = float_operand("#10 ");
+        assert!(result.is_err());
+        let result = operand("#10 ");
+        assert_eq!(
+            result,
+            Ok((
+                " ",
+                Token::IntegerOperand {
+                    value: 10,
+                    sign_bit: false
+                }
+            ))
+        );
     }
 
     #[test]
     fn test_parse_opcode() {
         // First tests that the opcode is detected and parsed correctly
         let result = opcode("load ");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, token) = result.unwrap();
         assert_eq!(token, Token::Op { code: OpCode::LOAD });
         assert_eq!(rest, " ");
 
+        let result = opcode("addf64 ");
+        assert_eq!(result, Ok((" ", Token::Op { code: OpCode::ADDF64 })));
+
         // Tests that an invalid opcode isn't recognized
         let result = opcode("aold ");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
     }
 
     #[test]
     fn test_program_to_bytes() -> Result<(), AssemblerError> {
         let result = program("load $0 #100\n");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (rest, program) = result.unwrap();
         println!("{}", rest);
         let mut bytecode = Vec::new();
         for instruction in program.instructions {
-            bytecode.extend(instruction.to_bytes(&mut SymbolTable::new())?);
+            bytecode.extend(instruction.to_bytes(&SymbolTable::new())?);
         }
 
         println!("{:?}", bytecode);
@@ -280,7 +467,7 @@ mod tests {
     #[test]
     fn test_parse_label_declaration() {
         let result = label_declaration("test:");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(
             token,
@@ -289,13 +476,37 @@ mod tests {
             }
         );
         let result = label_declaration("test");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+
+        let result = label_declaration("Loop_1: hlt");
+        assert_eq!(
+            result,
+            Ok((
+                " hlt",
+                Token::LabelDeclaration {
+                    name: "Loop_1".to_string()
+                }
+            ))
+        );
+        assert!(label_declaration("1loop:").is_err());
+    }
+
+    #[test]
+    fn test_parse_identifier() {
+        assert_eq!(identifier("loop_1 "), Ok((" ", "loop_1")));
+        assert_eq!(identifier("read_byte"), Ok(("", "read_byte")));
+        assert_eq!(identifier("L2:"), Ok((":", "L2")));
+        assert_eq!(identifier("_start"), Ok(("", "_start")));
+        assert_eq!(identifier(".L.end2"), Ok(("", ".L.end2")));
+        assert_eq!(identifier("a-b"), Ok(("-b", "a")));
+        assert!(identifier("2nd").is_err());
+        assert!(identifier("$0").is_err());
     }
 
     #[test]
     fn test_parse_label_usage() {
         let result = label_usage("@test");
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (_, token) = result.unwrap();
         assert_eq!(
             token,
@@ -304,7 +515,20 @@ mod tests {
             }
         );
         let result = label_usage("test");
-        assert_eq!(result.is_ok(), false);
+        assert!(result.is_err());
+
+        // Usages keep their case so they match declarations
+        let result = label_usage("@Read_Byte2 ");
+        assert_eq!(
+            result,
+            Ok((
+                " ",
+                Token::LabelUsage {
+                    name: "Read_Byte2".to_string()
+                }
+            ))
+        );
+        assert!(label_usage("@9lives").is_err());
     }
 
     #[test]
@@ -314,23 +538,114 @@ mod tests {
             result,
             Ok((
                 "",
-                AssemblerInstruction::new(
-                    Some(Token::Op { code: OpCode::LOAD }),
-                    [
-                        Some(Token::Register { id: 0 }),
-                        Some(Token::IntegerOperand {
-                            value: 100,
-                            sign_bit: false
-                        }),
+                AssemblerInstruction {
+                    span: Span::new(0, 12),
+                    operand_spans: [Some(Span::new(5, 7)), Some(Span::new(8, 12)), None],
+                    ..AssemblerInstruction::new(
+                        Some(Token::Op { code: OpCode::LOAD }),
+                        [
+                            Some(Token::Register { id: 0 }),
+                            Some(Token::IntegerOperand {
+                                value: 100,
+                                sign_bit: false
+                            }),
+                            None
+                        ],
+                        None,
                         None
-                    ],
-                    None,
-                    None
-                )
+                    )
+                }
             ))
         );
     }
 
+    #[test]
+    fn test_program_spans() {
+        let source = "\n.code\n  \nloop: inc $0\n    jmp @loop  \n";
+        let (rest, program) = program(source).unwrap();
+        assert_eq!(rest, "");
+        let spans: Vec<Span> = program.instructions.iter().map(|i| i.span).collect();
+        assert_eq!(
+            spans,
+            vec![Span::new(1, 6), Span::new(10, 22), Span::new(27, 36)]
+        );
+        assert_eq!(&source[10..22], "loop: inc $0");
+        assert_eq!(program.instructions[1].label_span(), Span::new(10, 14));
+        assert_eq!(program.instructions[2].operand_span(0), Span::new(31, 36));
+        assert_eq!(&source[31..36], "@loop");
+    }
+
+    #[test]
+    fn test_parse_comment() {
+        assert_eq!(comment("; note  \nhlt"), Ok(("\nhlt", "; note")));
+        assert_eq!(comment("// note"), Ok(("", "// note")));
+        assert!(comment("/ note").is_err());
+    }
+
+    #[test]
+    fn test_program_comments() {
+        let source = "; header\n.data\n.code // code\nloop: ; top\n    inc $0 ; bump\n\n    // spin\n    hlt\n; end";
+        let (rest, program) = program(source).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(program.instructions.len(), 4);
+        let comment = |text: &str, start: usize| Comment {
+            text: text.to_string(),
+            span: Span::new(start, start + text.len()),
+        };
+        assert_eq!(
+            program.comments,
+            vec![comment("; header", 0), comment("// spin", 64), comment("; end", 80)]
+        );
+        assert_eq!(program.instructions[1].comments, vec![comment("// code", 21)]);
+
+        // Comments aren't part of the instruction's span
+        let inc = &program.instructions[2];
+        assert_eq!(inc.comments, vec![comment("; top", 35), comment("; bump", 52)]);
+        assert_eq!(inc.span, Span::new(29, 51));
+        assert_eq!(inc.label_name(), Some("loop"));
+        assert_eq!(program.instructions[3].span, Span::new(76, 79));
+    }
+
+    #[test]
+    fn test_parse_directive_arguments() {
+        let (rest, word) = directive("lut: .word #1 , 0x10,@loop // table\n").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(word.span, Span::new(0, 26));
+        assert_eq!(word.label_name(), Some("lut"));
+        let arguments: Vec<Token> = word.arguments.into_iter().map(|(t, _)| t).collect();
+        assert_eq!(
+            arguments,
+            vec![
+                Token::IntegerOperand {
+                    value: 1,
+                    sign_bit: false
+                },
+                Token::IntegerOperand {
+                    value: 16,
+                    sign_bit: false
+                },
+                Token::LabelUsage {
+                    name: "loop".to_string()
+                },
+            ]
+        );
+
+        let (_, section) = directive(".data\n").unwrap();
+        assert!(section.arguments.is_empty());
+        assert!(directive(".byte #1,\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_program_error_span() {
+        use crate::assembler::parser::parse_program;
+
+        let source = ".code\n    inc $0\n\n    bogus $1\n    hlt\n";
+        assert_eq!(parse_program(source), Err(Span::new(22, 30)));
+        assert_eq!(&source[22..30], "bogus $1");
+        assert!(parse_program("   \n").is_err());
+        assert!(parse_program(".code\nhlt").is_ok());
+    }
+
     #[test]
     fn test_parse_instruction_form_two() {
         let result = instruction("hlt\n");
@@ -338,12 +653,15 @@ mod tests {
             result,
             Ok((
                 "",
-                AssemblerInstruction::new(
-                    Some(Token::Op { code: OpCode::HLT }),
-                    [None, None, None],
-                    None,
-                    None
-                )
+                AssemblerInstruction {
+                    span: Span::new(0, 3),
+                    ..AssemblerInstruction::new(
+                        Some(Token::Op { code: OpCode::HLT }),
+                        [None, None, None],
+                        None,
+                        None
+                    )
+                }
             ))
         );
     }
@@ -1,28 +1,48 @@
-use crate::opcode::OpCode;
+use crate::opcode::{OpCode, OperandKind};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Op { code: OpCode },
     Register { id: u8 },
-    IntegerOperand { value: u16, sign_bit: bool },
+    FloatRegister { id: u8 },
+    IntegerOperand { value: u32, sign_bit: bool },
+    FloatOperand { value: f64 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
     IRString { name: String },
 }
 
+impl Token {
+    /// What kind of operand this is, or `None` for tokens that can't be operands.
+    /// Label usages are taken to be code labels; only the symbol table knows better.
+    pub fn operand_kind(&self) -> Option<OperandKind> {
+        match self {
+            Token::Register { .. } => Some(OperandKind::Register),
+            Token::FloatRegister { .. } => Some(OperandKind::FloatRegister),
+            Token::IntegerOperand { .. } => Some(OperandKind::Integer),
+            Token::FloatOperand { .. } => Some(OperandKind::Float),
+            Token::LabelUsage { .. } => Some(OperandKind::Label),
+            Token::IRString { .. } => Some(OperandKind::String),
+            Token::Op { .. } | Token::LabelDeclaration { .. } | Token::Directive { .. } => None,
+        }
+    }
+}
+
 impl std::fmt::Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Token::Op { code } => write!(f, "Op: {}", code),
             Token::Register { id } => write!(f, "Register: {}", id),
+            Token::FloatRegister { id } => write!(f, "Float Register: {}", id),
             Token::IntegerOperand { value, sign_bit } => {
                 if *sign_bit {
-                    write!(f, "Int Operand: {}", value)
+                    write!(f, "Int Operand: -{}", value)
                 } else {
                     write!(f, "Int Operand: {}", value)
                 }
             }
+            Token::FloatOperand { value } => write!(f, "Float Operand: {}", value),
             Token::LabelDeclaration { name } => write!(f, "Label Decl: {}", name),
             Token::LabelUsage { name } => write!(f, "Label Usage: {}", name),
             Token::Directive { name } => write!(f, "Directive: {}", name),
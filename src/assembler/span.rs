@@ -0,0 +1,103 @@
+/// A range of byte offsets into the source being assembled
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// The same span, moved `offset` bytes further into the source.
+    pub fn shift(self, offset: usize) -> Self {
+        Self::new(self.start + offset, self.end + offset)
+    }
+
+    /// The 1-based line and column where the span starts. Columns count characters.
+    pub fn line_col(&self, source: &str) -> (usize, usize) {
+        let before = &source[..self.start.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (line, before[line_start..].chars().count() + 1)
+    }
+
+    pub fn line(&self, source: &str) -> usize {
+        self.line_col(source).0
+    }
+
+    /// The part of `source` the span covers
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        source.get(self.start..self.end).unwrap_or("")
+    }
+}
+
+/// Renders `message` the way rustc does, with the location, the line the span starts
+/// on and carets under the spanned part of it:
+/// ```text
+/// error: Symbol already declared: loop
+///  --> test.lr:5:1
+///   |
+/// 5 | loop: inc $0
+///   | ^^^^
+/// ```
+pub fn render_diagnostic(message: &str, name: &str, source: &str, span: Span) -> String {
+    let (line, column) = span.line_col(source);
+    let text = source.lines().nth(line - 1).unwrap_or("");
+    // Spans running onto later lines are underlined to the end of the first one
+    let underlined = source
+        .get(span.start..span.end)
+        .map_or(0, |s| s.lines().next().unwrap_or("").chars().count());
+    let available = text.chars().count().saturating_sub(column - 1);
+    let carets = underlined.min(available).max(1);
+    let gutter = " ".repeat(line.to_string().len());
+    format!(
+        "error: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+        message,
+        gutter,
+        name,
+        line,
+        column,
+        gutter,
+        line,
+        text,
+        gutter,
+        " ".repeat(column - 1),
+        "^".repeat(carets)
+    )
+}
+
+/// Tests for span
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_col() {
+        let source = "ab\n\ncd ef\n";
+        assert_eq!(Span::new(0, 1).line_col(source), (1, 1));
+        assert_eq!(Span::new(3, 3).line_col(source), (2, 1));
+        assert_eq!(Span::new(7, 9).line_col(source), (3, 4));
+        assert_eq!(Span::new(10, 10).line_col(source), (4, 1));
+        assert_eq!(Span::new(2, 4).shift(5), Span::new(7, 9));
+    }
+
+    #[test]
+    fn test_render_diagnostic() {
+        let source = ".data\n.code\n    load $0 @nowhere\n";
+        let span = Span::new(24, 32);
+        assert_eq!(
+            render_diagnostic("Undefined label: nowhere", "test.lr", source, span),
+            "error: Undefined label: nowhere\n \
+             --> test.lr:3:13\n  \
+             |\n\
+             3 |     load $0 @nowhere\n  \
+             |             ^^^^^^^^\n"
+        );
+
+        // An empty span at the end of the source still gets a caret
+        let rendered = render_diagnostic("Insufficient sections", "x.lr", "hlt", Span::new(3, 3));
+        assert!(rendered.ends_with("1 | hlt\n  |    ^\n"), "{}", rendered);
+    }
+}
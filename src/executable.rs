@@ -0,0 +1,271 @@
+use std::io::{Cursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// Magic number identifying an Iridium executable (.irb)
+pub const MAGIC: [u8; 4] = *b"IRID";
+/// Current version of the executable format
+pub const VERSION: u16 = 1;
+/// Magic (4), version (2), section count (2), entry offset (4)
+pub const HEADER_LEN: usize = 12;
+/// Kind (1), reserved (3), file offset (4), length (4)
+pub const SECTION_ENTRY_LEN: usize = 12;
+
+/// Layout:
+/// ```text
+/// header        magic | version | section count | entry offset
+/// section table kind  | reserved | offset | length   (one entry per section)
+/// section data
+/// ```
+/// All integers are big-endian, matching the encoding of instruction immediates.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Executable {
+    pub version: u16,
+    pub entry: u32,
+    pub code: Vec<u8>,
+    pub ro: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SectionKind {
+    Code = 1,
+    ReadOnly = 2,
+}
+
+impl SectionKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(SectionKind::Code),
+            2 => Some(SectionKind::ReadOnly),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExecutableError {
+    BadMagic { found: Vec<u8> },
+    UnsupportedVersion { version: u16 },
+    Truncated,
+    UnknownSection { kind: u8 },
+    DuplicateSection { kind: SectionKind },
+    SectionOutOfBounds { kind: SectionKind },
+    MissingCodeSection,
+    EntryOutOfBounds { entry: u32 },
+}
+
+impl std::fmt::Display for ExecutableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExecutableError::BadMagic { found } => {
+                write!(f, "Bad magic number {:02X?}, not an Iridium executable", found)
+            }
+            ExecutableError::UnsupportedVersion { version } => {
+                write!(
+                    f,
+                    "Unsupported executable version {} (supported: {})",
+                    version, VERSION
+                )
+            }
+            ExecutableError::Truncated => write!(f, "Executable is truncated"),
+            ExecutableError::UnknownSection { kind } => {
+                write!(f, "Unknown section kind {}", kind)
+            }
+            ExecutableError::DuplicateSection { kind } => {
+                write!(f, "Duplicate {:?} section", kind)
+            }
+            ExecutableError::SectionOutOfBounds { kind } => {
+                write!(f, "{:?} section extends past the end of the file", kind)
+            }
+            ExecutableError::MissingCodeSection => write!(f, "Executable has no code section"),
+            ExecutableError::EntryOutOfBounds { entry } => {
+                write!(f, "Entry offset {} is outside the code section", entry)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExecutableError {}
+
+impl From<std::io::Error> for ExecutableError {
+    fn from(_: std::io::Error) -> Self {
+        ExecutableError::Truncated
+    }
+}
+
+impl Executable {
+    pub fn new(code: Vec<u8>, ro: Vec<u8>, entry: u32) -> Self {
+        Self {
+            version: VERSION,
+            entry,
+            code,
+            ro,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let sections = [
+            (SectionKind::Code, &self.code),
+            (SectionKind::ReadOnly, &self.ro),
+        ];
+        let mut bytes = Vec::with_capacity(
+            HEADER_LEN + SECTION_ENTRY_LEN * sections.len() + self.code.len() + self.ro.len(),
+        );
+        bytes.extend(MAGIC);
+        // Writes into a Vec can't fail
+        bytes.write_u16::<BigEndian>(self.version).unwrap();
+        bytes.write_u16::<BigEndian>(sections.len() as u16).unwrap();
+        bytes.write_u32::<BigEndian>(self.entry).unwrap();
+
+        let mut offset = (HEADER_LEN + SECTION_ENTRY_LEN * sections.len()) as u32;
+        for (kind, data) in sections {
+            bytes.push(kind as u8);
+            bytes.extend([0, 0, 0]);
+            bytes.write_u32::<BigEndian>(offset).unwrap();
+            bytes.write_u32::<BigEndian>(data.len() as u32).unwrap();
+            offset += data.len() as u32;
+        }
+        for (_, data) in sections {
+            bytes.extend(data.iter());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ExecutableError> {
+        let mut reader = Cursor::new(bytes);
+        let mut magic = [0u8; 4];
+        if reader.read_exact(&mut magic).is_err() || magic != MAGIC {
+            return Err(ExecutableError::BadMagic {
+                found: bytes.iter().take(4).cloned().collect(),
+            });
+        }
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != VERSION {
+            return Err(ExecutableError::UnsupportedVersion { version });
+        }
+        let section_count = reader.read_u16::<BigEndian>()?;
+        let entry = reader.read_u32::<BigEndian>()?;
+
+        let mut code = None;
+        let mut ro = None;
+        for _ in 0..section_count {
+            let raw_kind = reader.read_u8()?;
+            let mut reserved = [0u8; 3];
+            reader.read_exact(&mut reserved)?;
+            let offset = reader.read_u32::<BigEndian>()? as usize;
+            let len = reader.read_u32::<BigEndian>()? as usize;
+
+            let kind = match SectionKind::from_u8(raw_kind) {
+                Some(kind) => kind,
+                None => return Err(ExecutableError::UnknownSection { kind: raw_kind }),
+            };
+            let data = match offset.checked_add(len).and_then(|end| bytes.get(offset..end)) {
+                Some(data) => data.to_vec(),
+                None => return Err(ExecutableError::SectionOutOfBounds { kind }),
+            };
+            let slot = match kind {
+                SectionKind::Code => &mut code,
+                SectionKind::ReadOnly => &mut ro,
+            };
+            if slot.is_some() {
+                return Err(ExecutableError::DuplicateSection { kind });
+            }
+            *slot = Some(data);
+        }
+
+        let code = match code {
+            Some(code) => code,
+            None => return Err(ExecutableError::MissingCodeSection),
+        };
+        if entry as usize > code.len() {
+            return Err(ExecutableError::EntryOutOfBounds { entry });
+        }
+        Ok(Self {
+            version,
+            entry,
+            code,
+            ro: ro.unwrap_or_default(),
+        })
+    }
+}
+
+/// Tests for executable
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Executable {
+        Executable::new(vec![1, 0, 0, 5, 5, 0, 0, 0], b"Hi\0".to_vec(), 4)
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), ExecutableError> {
+        let exe = sample();
+        let bytes = exe.to_bytes();
+        assert_eq!(&bytes[0..4], &MAGIC);
+        assert_eq!(bytes.len(), HEADER_LEN + 2 * SECTION_ENTRY_LEN + 8 + 3);
+        assert_eq!(Executable::from_bytes(&bytes)?, exe);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let mut bytes = sample().to_bytes();
+        bytes[0] = b'X';
+        assert!(matches!(
+            Executable::from_bytes(&bytes),
+            Err(ExecutableError::BadMagic { .. })
+        ));
+        assert!(matches!(
+            Executable::from_bytes(&[]),
+            Err(ExecutableError::BadMagic { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut exe = sample();
+        exe.version = VERSION + 1;
+        assert_eq!(
+            Executable::from_bytes(&exe.to_bytes()),
+            Err(ExecutableError::UnsupportedVersion {
+                version: VERSION + 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let bytes = sample().to_bytes();
+        assert_eq!(
+            Executable::from_bytes(&bytes[..HEADER_LEN + 5]),
+            Err(ExecutableError::Truncated)
+        );
+        assert_eq!(
+            Executable::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ExecutableError::SectionOutOfBounds {
+                kind: SectionKind::ReadOnly
+            })
+        );
+    }
+
+    #[test]
+    fn test_entry_out_of_bounds() {
+        let mut exe = sample();
+        exe.entry = 12;
+        assert_eq!(
+            Executable::from_bytes(&exe.to_bytes()),
+            Err(ExecutableError::EntryOutOfBounds { entry: 12 })
+        );
+    }
+
+    #[test]
+    fn test_unknown_section() {
+        let mut bytes = sample().to_bytes();
+        bytes[HEADER_LEN + SECTION_ENTRY_LEN] = 9;
+        assert_eq!(
+            Executable::from_bytes(&bytes),
+            Err(ExecutableError::UnknownSection { kind: 9 })
+        );
+    }
+}
@@ -0,0 +1,636 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use crate::vm::{Breakpoint, RunOutcome, StopReason, VmErrorKind, VM};
+
+/// Number of general purpose registers reported to GDB; `pc` follows them
+pub const GDB_REGISTERS: usize = 32;
+/// GDB register number of the program counter
+pub const PC_REGISTER: usize = GDB_REGISTERS;
+/// Base of the program (code) region in the address space seen by GDB
+pub const CODE_BASE: usize = 0x0000_0000;
+/// Base of the heap region in the address space seen by GDB
+pub const HEAP_BASE: usize = 0x1000_0000;
+/// Base of the read-only data region in the address space seen by GDB
+pub const RO_BASE: usize = 0x2000_0000;
+/// Instructions executed by `c` between checks for an interrupt from GDB
+const CONTINUE_CHUNK: u64 = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+const SIGXCPU: u8 = 24;
+
+/// A connection GDB talks to the stub over
+pub trait Transport: Read + Write {
+    /// Consumes a pending interrupt request (0x03) without blocking.
+    fn poll_interrupt(&mut self) -> io::Result<bool>;
+}
+
+impl Transport for TcpStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0u8; 1];
+        let pending = loop {
+            match self.peek(&mut byte) {
+                // Skip acks still queued from earlier replies
+                Ok(1) if matches!(byte[0], b'+' | b'-') => {
+                    self.read_exact(&mut byte)?;
+                }
+                Ok(1) if byte[0] == 0x03 => break self.read_exact(&mut byte).map(|_| true),
+                Ok(_) => break Ok(false),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(false),
+                Err(e) => break Err(e),
+            }
+        };
+        self.set_nonblocking(false)?;
+        pending
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0u8; 1];
+        // Unix sockets can't peek, so anything other than an interrupt (i.e. acks) is dropped
+        let pending = loop {
+            match self.read(&mut byte) {
+                Ok(1) if byte[0] == 0x03 => break Ok(true),
+                Ok(1) => continue,
+                Ok(_) => break Ok(false),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(false),
+                Err(e) => break Err(e),
+            }
+        };
+        self.set_nonblocking(false)?;
+        pending
+    }
+}
+
+/// Serves a single GDB Remote Serial Protocol session for a VM.
+///
+/// GDB sees registers `r0`..`r31` followed by `pc`, each 32 bits and big-endian like the
+/// VM's memory. The program, heap and read-only data are mapped at `CODE_BASE`,
+/// `HEAP_BASE` and `RO_BASE`. Software and hardware breakpoints map to PC breakpoints,
+/// and write watchpoints on the heap map to heap watchpoints.
+pub struct GdbStub {
+    vm: VM,
+    /// VM breakpoint ids, keyed by the `Z` packet type, address and kind that created them
+    breakpoints: HashMap<(u8, usize, usize), usize>,
+    last_stop: String,
+    no_ack: bool,
+    attached: bool,
+}
+
+impl GdbStub {
+    pub fn new(vm: VM) -> Self {
+        Self {
+            vm,
+            breakpoints: HashMap::new(),
+            last_stop: format!("S{:02x}", SIGTRAP),
+            no_ack: false,
+            attached: true,
+        }
+    }
+
+    /// Answers packets until GDB detaches, kills the program or closes the connection.
+    pub fn serve<T: Transport>(&mut self, conn: &mut T) -> io::Result<()> {
+        while self.attached {
+            let packet = match self.read_packet(conn)? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+            if let Some(reply) = self.handle(&packet, conn)? {
+                write_packet(conn, &reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next packet, acknowledging it unless no-ack mode is on.
+    /// Returns `None` once the connection is closed.
+    fn read_packet<T: Transport>(&mut self, conn: &mut T) -> io::Result<Option<String>> {
+        loop {
+            let byte = match read_byte(conn)? {
+                Some(byte) => byte,
+                None => return Ok(None),
+            };
+            match byte {
+                b'$' => {}
+                0x03 => return Ok(Some("\x03".to_string())),
+                // Acks for our replies, and line noise
+                _ => continue,
+            }
+            let mut data = vec![];
+            loop {
+                match read_byte(conn)? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let checksum = match (read_byte(conn)?, read_byte(conn)?) {
+                (Some(a), Some(b)) => [a, b],
+                _ => return Ok(None),
+            };
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                == Some(packet_checksum(&data));
+            if !self.no_ack {
+                conn.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    /// Handles one packet, returning the reply to send, if any.
+    /// Unsupported packets get the empty reply the protocol expects.
+    fn handle<T: Transport>(&mut self, packet: &str, conn: &mut T) -> io::Result<Option<String>> {
+        let reply = match packet {
+            "\x03" => format!("S{:02x}", SIGINT),
+            "?" => self.last_stop.clone(),
+            "g" => self.read_registers(),
+            "k" => {
+                self.attached = false;
+                return Ok(None);
+            }
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ if packet.starts_with('D') => {
+                self.attached = false;
+                "OK".to_string()
+            }
+            _ if packet.starts_with("qSupported") => {
+                "PacketSize=4000;QStartNoAckMode+;qXfer:features:read+".to_string()
+            }
+            _ if packet.starts_with('H') => "OK".to_string(),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                self.read_target_xml(&packet["qXfer:features:read:target.xml:".len()..])
+            }
+            _ if packet.starts_with('p') => self.read_register(&packet[1..]),
+            _ if packet.starts_with('P') => self.write_register(&packet[1..]),
+            _ if packet.starts_with('G') => self.write_registers(&packet[1..]),
+            _ if packet.starts_with('m') => self.read_memory(&packet[1..]),
+            _ if packet.starts_with('M') => self.write_memory(&packet[1..]),
+            _ if packet.starts_with('Z') => self.insert_breakpoint(&packet[1..]),
+            _ if packet.starts_with('z') => self.remove_breakpoint(&packet[1..]),
+            _ if packet.starts_with('s') => {
+                self.resume_at(&packet[1..]);
+                let reply = self.step();
+                self.last_stop = reply.clone();
+                reply
+            }
+            _ if packet.starts_with('c') => {
+                self.resume_at(&packet[1..]);
+                let reply = self.continue_execution(conn)?;
+                self.last_stop = reply.clone();
+                reply
+            }
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn register_value(&self, register: usize) -> Option<u32> {
+        match register {
+            PC_REGISTER => Some(*self.vm.read_pc() as u32),
+            _ => self.vm.read_registers().get(register).map(|r| *r as u32),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..=PC_REGISTER)
+            .filter_map(|r| self.register_value(r))
+            .map(|value| format!("{:08x}", value))
+            .collect()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        match parse_hex(args).and_then(|r| self.register_value(r)) {
+            Some(value) => format!("{:08x}", value),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn set_register(&mut self, register: usize, value: u32) -> bool {
+        match register {
+            PC_REGISTER => {
+                self.vm.set_pc(value as usize);
+                true
+            }
+            _ => self.vm.set_register(register, value as i32).is_ok(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let written = args.split_once('=').and_then(|(register, value)| {
+            let register = parse_hex(register)?;
+            let value = u32::from_str_radix(value, 16).ok()?;
+            Some(self.set_register(register, value))
+        });
+        match written {
+            Some(true) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let values = match decode_hex(args) {
+            Some(bytes) if bytes.len() == (PC_REGISTER + 1) * 4 => bytes,
+            _ => return "E01".to_string(),
+        };
+        for (register, value) in values.chunks(4).enumerate() {
+            let value = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+            self.set_register(register, value);
+        }
+        "OK".to_string()
+    }
+
+    /// Splits an address into the region it falls in and the offset within it.
+    fn region(&self, address: usize) -> (&[u8], usize) {
+        if address >= RO_BASE {
+            (self.vm.read_ro_data(), address - RO_BASE)
+        } else if address >= HEAP_BASE {
+            (self.vm.read_heap(), address - HEAP_BASE)
+        } else {
+            (self.vm.read_program(), address - CODE_BASE)
+        }
+    }
+
+    /// Reads up to the requested length, stopping at the end of the region.
+    fn read_memory(&self, args: &str) -> String {
+        let (address, len) = match parse_address_len(args) {
+            Some(range) => range,
+            None => return "E01".to_string(),
+        };
+        let (region, offset) = self.region(address);
+        match region.get(offset..) {
+            Some(bytes) if !bytes.is_empty() || len == 0 => {
+                encode_hex(&bytes[..len.min(bytes.len())])
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Only the heap is writable; the program and read-only data are left as loaded.
+    fn write_memory(&mut self, args: &str) -> String {
+        let written = args.split_once(':').and_then(|(range, data)| {
+            let (address, len) = parse_address_len(range)?;
+            let bytes = decode_hex(data).filter(|bytes| bytes.len() == len)?;
+            if !(HEAP_BASE..RO_BASE).contains(&address) {
+                return None;
+            }
+            self.vm.write_heap(address - HEAP_BASE, &bytes).ok()
+        });
+        match written {
+            Some(_) => "OK".to_string(),
+            None => "E01".to_string(),
+        }
+    }
+
+    /// Parses `type,address,kind` from a `Z`/`z` packet.
+    fn parse_breakpoint(args: &str) -> Option<(u8, usize, usize)> {
+        let mut parts = args.splitn(3, ',');
+        let kind = parts.next()?.parse().ok()?;
+        let address = parse_hex(parts.next()?)?;
+        let len = parse_hex(parts.next()?.split(';').next()?)?;
+        Some((kind, address, len))
+    }
+
+    fn insert_breakpoint(&mut self, args: &str) -> String {
+        let key = match Self::parse_breakpoint(args) {
+            Some(key) => key,
+            None => return "E01".to_string(),
+        };
+        let breakpoint = match key {
+            (0 | 1, address, _) if address < HEAP_BASE => Breakpoint::Pc(address - CODE_BASE),
+            (2, address, len) if (HEAP_BASE..RO_BASE).contains(&address) && len > 0 => {
+                Breakpoint::Heap {
+                    address: address - HEAP_BASE,
+                    len,
+                }
+            }
+            (0..=2, _, _) => return "E01".to_string(),
+            _ => return String::new(),
+        };
+        if !self.breakpoints.contains_key(&key) {
+            let id = self.vm.add_breakpoint(breakpoint);
+            self.breakpoints.insert(key, id);
+        }
+        "OK".to_string()
+    }
+
+    fn remove_breakpoint(&mut self, args: &str) -> String {
+        let key = match Self::parse_breakpoint(args) {
+            Some(key) => key,
+            None => return "E01".to_string(),
+        };
+        if key.0 > 2 {
+            return String::new();
+        }
+        if let Some(id) = self.breakpoints.remove(&key) {
+            self.vm.remove_breakpoint(id);
+        }
+        "OK".to_string()
+    }
+
+    /// Moves the program counter if `s`/`c` carried a resume address.
+    fn resume_at(&mut self, args: &str) {
+        if let Some(address) = parse_hex(args) {
+            self.vm.set_pc(address);
+        }
+    }
+
+    fn step(&mut self) -> String {
+        let outcome = match self.vm.step() {
+            Ok((false, _)) => return format!("S{:02x}", SIGTRAP),
+            Ok((true, _)) if self.vm.is_halted() => RunOutcome::Halted,
+            Ok((true, _)) => RunOutcome::EndOfProgram,
+            Err(e) if e.kind == VmErrorKind::OutOfFuel => RunOutcome::BudgetExhausted,
+            Err(e) => RunOutcome::Faulted(e),
+        };
+        Self::stop_reply(&outcome)
+    }
+
+    /// Runs in chunks so an interrupt from GDB can stop a program that never halts.
+    fn continue_execution<T: Transport>(&mut self, conn: &mut T) -> io::Result<String> {
+        loop {
+            let outcome = self.vm.run_limited(CONTINUE_CHUNK);
+            // A recorded fault means fuel ran out rather than the chunk ending
+            if outcome != RunOutcome::BudgetExhausted || self.vm.read_fault().is_some() {
+                return Ok(Self::stop_reply(&outcome));
+            }
+            if conn.poll_interrupt()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    fn stop_reply(outcome: &RunOutcome) -> String {
+        match outcome {
+            RunOutcome::Halted | RunOutcome::EndOfProgram => "W00".to_string(),
+            RunOutcome::Faulted(e) => format!("S{:02x}", fault_signal(&e.kind)),
+            RunOutcome::BudgetExhausted => format!("S{:02x}", SIGXCPU),
+            RunOutcome::Stopped(StopReason::Heap { address, .. }) => {
+                format!("T{:02x}watch:{:x};", SIGTRAP, HEAP_BASE + address)
+            }
+            RunOutcome::Stopped(_) => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    /// Serves the target description in the chunks GDB asks for.
+    fn read_target_xml(&self, args: &str) -> String {
+        let (offset, len) = match parse_address_len(args) {
+            Some(range) => range,
+            None => return "E01".to_string(),
+        };
+        let xml = target_xml();
+        let chunk = xml.get(offset..).unwrap_or("");
+        if chunk.len() <= len {
+            format!("l{}", chunk)
+        } else {
+            format!("m{}", &chunk[..len])
+        }
+    }
+}
+
+fn fault_signal(kind: &VmErrorKind) -> u8 {
+    match kind {
+        VmErrorKind::IllegalOpcode { .. } | VmErrorKind::TruncatedInstruction => SIGILL,
+        VmErrorKind::DivideByZero => SIGFPE,
+        VmErrorKind::OutOfFuel => SIGXCPU,
+        _ => SIGSEGV,
+    }
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n<feature name=\"org.iridium.core\">\n",
+    );
+    for register in 0..GDB_REGISTERS {
+        xml.push_str(&format!(
+            "<reg name=\"r{}\" bitsize=\"32\" type=\"int32\"/>\n",
+            register
+        ));
+    }
+    xml.push_str("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/>\n</feature>\n</target>\n");
+    xml
+}
+
+fn read_byte<T: Read>(conn: &mut T) -> io::Result<Option<u8>> {
+    let mut byte = [0u8; 1];
+    match conn.read_exact(&mut byte) {
+        Ok(()) => Ok(Some(byte[0])),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_packet<T: Write>(conn: &mut T, data: &str) -> io::Result<()> {
+    write!(conn, "${}#{:02x}", data, packet_checksum(data.as_bytes()))?;
+    conn.flush()
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// Parses the `address,length` pair used by memory and qXfer packets.
+fn parse_address_len(s: &str) -> Option<(usize, usize)> {
+    let (address, len) = s.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(len)?))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Tests for gdb
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::OpCode::*;
+    use std::{net::TcpListener, thread};
+
+    /// A scripted GDB client talking to a stub on a local port
+    struct Client {
+        stream: TcpStream,
+        server: thread::JoinHandle<()>,
+    }
+
+    impl Client {
+        fn connect(program: Vec<u8>) -> Client {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let mut vm = VM::new();
+                vm.add_program(&mut program.clone());
+                let (mut conn, _) = listener.accept().unwrap();
+                conn.set_nodelay(true).unwrap();
+                GdbStub::new(vm).serve(&mut conn).unwrap();
+            });
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            Client { stream, server }
+        }
+
+        fn send(&mut self, data: &str) -> String {
+            write_packet(&mut self.stream, data).unwrap();
+            assert_eq!(read_byte(&mut self.stream).unwrap(), Some(b'+'));
+            self.receive()
+        }
+
+        fn receive(&mut self) -> String {
+            assert_eq!(read_byte(&mut self.stream).unwrap(), Some(b'$'));
+            let mut data = vec![];
+            loop {
+                match read_byte(&mut self.stream).unwrap().unwrap() {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16);
+            assert_eq!(checksum, Ok(packet_checksum(&data)));
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(data).unwrap()
+        }
+
+        fn detach(mut self) {
+            assert_eq!(self.send("D"), "OK");
+            self.server.join().unwrap();
+        }
+    }
+
+    fn counter_program() -> Vec<u8> {
+        vec![
+            LOAD as u8, 0, 0, 5, //
+            INC as u8, 0, 0, 0, //
+            INC as u8, 0, 0, 0, //
+            HLT as u8, 0, 0, 0,
+        ]
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(packet_checksum(b"OK"), 0x9a);
+        assert_eq!(packet_checksum(b""), 0);
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(encode_hex(&[0x00, 0xAB, 0x10]), "00ab10");
+        assert_eq!(decode_hex("00ab10"), Some(vec![0x00, 0xAB, 0x10]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+
+    #[test]
+    fn test_handshake_and_registers() {
+        let mut client = Client::connect(counter_program());
+        assert!(client.send("qSupported:multiprocess+").contains("qXfer:features:read+"));
+        assert_eq!(client.send("?"), "S05");
+        let registers = client.send("g");
+        assert_eq!(registers.len(), (PC_REGISTER + 1) * 8);
+
+        let xml = client.send("qXfer:features:read:target.xml:0,10000");
+        assert!(xml.starts_with('l') && xml.contains("name=\"pc\""));
+        assert!(client.send("qXfer:features:read:target.xml:0,8").starts_with('m'));
+
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p0"), "00000005");
+        assert_eq!(client.send("p20"), "00000004");
+        assert_eq!(client.send("P1=ffffffff"), "OK");
+        assert_eq!(client.send("p1"), "ffffffff");
+        assert_eq!(client.send("P40=0"), "E01");
+        client.detach();
+    }
+
+    #[test]
+    fn test_breakpoints_and_continue() {
+        let mut client = Client::connect(counter_program());
+        assert_eq!(client.send("Z0,8,4"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p20"), "00000008");
+        assert_eq!(client.send("p0"), "00000006");
+        assert_eq!(client.send("?"), "S05");
+        assert_eq!(client.send("z0,8,4"), "OK");
+        assert_eq!(client.send("c"), "W00");
+        assert_eq!(client.send("p0"), "00000007");
+        client.detach();
+    }
+
+    #[test]
+    fn test_memory_and_watchpoints() {
+        let mut client = Client::connect(vec![
+            LOAD as u8, 0, 0, 8, //
+            ALOC as u8, 0, 0, 0, //
+            LOAD as u8, 1, 0, 2, //
+            SETM as u8, 1, 1, 1, // heap[2] = 2
+            HLT as u8, 0, 0, 0,
+        ]);
+        assert_eq!(client.send("m0,4"), "00000008");
+        assert_eq!(client.send("m10000000,4"), "E01");
+        assert_eq!(client.send("Z2,10000002,1"), "OK");
+        // Allocation first brings the watched byte into existence
+        assert_eq!(client.send("c"), "T05watch:10000002;");
+        assert_eq!(client.send("m10000000,4"), "00000000");
+        assert_eq!(client.send("M10000000,2:abcd"), "OK");
+        assert_eq!(client.send("M0,1:00"), "E01");
+        assert_eq!(client.send("c"), "T05watch:10000002;");
+        assert_eq!(client.send("m10000000,10"), "abcd020000000000");
+        assert_eq!(client.send("Z3,10000002,1"), "");
+        client.detach();
+    }
+
+    #[test]
+    fn test_faults_and_interrupt() {
+        let mut client = Client::connect(vec![
+            JMP as u8, 0, 0, 0, // $0 is 0, so this loops forever
+            DIV as u8, 0, 0, 0,
+        ]);
+        // The reply only comes once the loop is interrupted
+        write_packet(&mut client.stream, "c").unwrap();
+        assert_eq!(read_byte(&mut client.stream).unwrap(), Some(b'+'));
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(client.receive(), "S02");
+        assert_eq!(client.send("P20=4"), "OK");
+        assert_eq!(client.send("s"), "S08");
+        assert_eq!(client.send("p20"), "00000004");
+        client.detach();
+    }
+}
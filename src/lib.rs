@@ -0,0 +1,67 @@
+//! Iridium is a register-based virtual machine with its own assembly language.
+//!
+//! The usual embedding flow is to assemble a source string into an executable image,
+//! load it into a [`VM`], run it under an instruction or fuel limit and read back its
+//! registers and heap:
+//!
+//! ```
+//! use iridium::{assemble, RunOutcome, VM};
+//!
+//! let image = assemble(".data\n.code\nload $0 #40\ninc $0\ninc $0\nhlt").unwrap();
+//! let mut vm = VM::new();
+//! vm.load_executable(&image).unwrap();
+//! assert_eq!(vm.run_limited(100), RunOutcome::Halted);
+//! assert_eq!(vm.read_registers()[0], 42);
+//! ```
+//!
+//! Guest programs call back into the embedding application with `syscall #n`,
+//! dispatched to functions registered with [`VM::register_host_function`].
+
+#[macro_use]
+extern crate enum_primitive;
+extern crate num_traits;
+
+pub mod assembler;
+pub mod executable;
+pub mod gdb;
+pub mod opcode;
+pub mod repl;
+pub mod vm;
+
+pub use assembler::{Assembler, AssemblerError, SymbolTable};
+pub use executable::{Executable, ExecutableError};
+pub use opcode::OpCode;
+pub use vm::{HostContext, RunOutcome, VmError, VmErrorKind, VM};
+
+/// Assembles source into an executable image for [`VM::load_executable`].
+pub fn assemble(source: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+    let mut assembler = Assembler::new();
+    match assembler.assemble(source) {
+        Ok(_) => Ok(assembler.to_executable().to_bytes()),
+        Err(errors) => Err(errors.clone()),
+    }
+}
+
+/// Tests for lib
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble_and_run() {
+        let image = assemble(".data\n.code\nload $0 #3\nload $1 #4\nmul $0 $1 $2").unwrap();
+        let mut vm = VM::new();
+        vm.load_executable(&image).unwrap();
+        assert_eq!(vm.run_limited(10), RunOutcome::EndOfProgram);
+        assert_eq!(vm.read_registers()[2], 12);
+
+        vm.load_bytecode(vec![OpCode::INC as u8, 5, 0, 0]);
+        assert_eq!(vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(vm.read_registers()[..6], [0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert!(!assemble("load $0 #1").unwrap_err().is_empty());
+    }
+}
@@ -1,23 +1,301 @@
-use std::process::ExitCode;
+use std::{fs, io, path::Path, process::ExitCode};
 
-#[macro_use]
-extern crate enum_primitive;
-extern crate num_traits;
-//extern crate num;
+use clap::{Arg, ArgMatches, Command};
 
-mod assembler;
-mod opcode;
-mod repl;
-mod vm;
+use iridium::assembler::{disassembler::disassemble, Assembler, SymbolTable};
+use iridium::executable::Executable;
+use iridium::gdb::GdbStub;
+use iridium::opcode::OpCode;
+use iridium::repl::REPL;
+use iridium::vm::{read_binary_trace, RunOutcome, TraceFormat, VmErrorKind, VM};
+
+fn cli() -> Command<'static> {
+    Command::new("iridium")
+        .about("Iridium virtual machine, assembler and REPL")
+        .subcommand(
+            Command::new("run")
+                .about("Run a .lr source file or .irb executable")
+                .arg(Arg::new("file").required(true))
+                .arg(
+                    Arg::new("trace")
+                        .long("trace")
+                        .help("Print each executed instruction to stderr"),
+                )
+                .arg(
+                    Arg::new("trace-file")
+                        .long("trace-file")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Record every executed instruction and its effects to PATH"),
+                )
+                .arg(
+                    Arg::new("trace-format")
+                        .long("trace-format")
+                        .takes_value(true)
+                        .possible_values(["text", "binary"])
+                        .default_value("text")
+                        .requires("trace-file")
+                        .help("Format of the --trace-file record"),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .help("Print instruction counts per opcode, pc and branch to stderr"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Stop with an error after executing N instructions"),
+                )
+                .arg(
+                    Arg::new("fuel")
+                        .long("fuel")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Meter execution, stopping with an error once N fuel is spent"),
+                )
+                .arg(
+                    Arg::new("cost")
+                        .long("cost")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .value_name("OPCODE=N")
+                        .help("Fuel charged for each execution of OPCODE (default 1)"),
+                ),
+        )
+        .subcommand(
+            Command::new("asm")
+                .about("Assemble a .lr source file into a .irb executable")
+                .arg(Arg::new("input").required(true))
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .takes_value(true)
+                        .help("Output path (defaults to the input with an .irb extension)"),
+                ),
+        )
+        .subcommand(
+            Command::new("disasm")
+                .about("Disassemble a .irb executable")
+                .arg(Arg::new("file").required(true)),
+        )
+        .subcommand(
+            Command::new("trace")
+                .about("Print a binary execution trace as text")
+                .arg(Arg::new("file").required(true)),
+        )
+        .subcommand(
+            Command::new("debug")
+                .about("Serve a program to GDB over the remote serial protocol")
+                .arg(Arg::new("file").required(true))
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .takes_value(true)
+                        .value_name("PORT")
+                        .default_value("1234")
+                        .help("Local TCP port to listen on"),
+                )
+                .arg(
+                    Arg::new("socket")
+                        .long("socket")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .conflicts_with("port")
+                        .help("Listen on a Unix socket instead of a TCP port"),
+                ),
+        )
+        .subcommand(Command::new("repl").about("Start the interactive REPL (default)"))
+}
 
 fn main() -> ExitCode {
-    println!("Welcome to the VM!");
-    let mut repl = repl::REPL::new();
-    return match repl.run() {
+    let matches = cli().get_matches();
+    let result = match matches.subcommand() {
+        Some(("run", args)) => run(args),
+        Some(("asm", args)) => asm(args),
+        Some(("disasm", args)) => disasm(args),
+        Some(("trace", args)) => trace(args),
+        Some(("debug", args)) => debug(args),
+        _ => repl(),
+    };
+    match result {
         Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
-            println!("Error: {}", e);
+            eprintln!("Error: {}", e);
             ExitCode::FAILURE
         }
+    }
+}
+
+fn repl() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Welcome to the VM!");
+    let mut repl = REPL::new();
+    repl.run()
+}
+
+/// Assembles a source file, or reads a prebuilt executable, into an executable image.
+/// Symbols are only available when assembling from source.
+fn load_image(path: &Path) -> Result<(Vec<u8>, Option<SymbolTable>), Box<dyn std::error::Error>> {
+    if path.extension().is_some_and(|ext| ext == "irb") {
+        return Ok((fs::read(path)?, None));
+    }
+    let source = fs::read_to_string(path)?;
+    let mut assembler = Assembler::new();
+    if let Err(errors) = assembler.assemble(&source) {
+        let name = path.display().to_string();
+        let messages: Vec<String> = errors.iter().map(|e| e.render(&name, &source)).collect();
+        return Err(format!(
+            "Failed to assemble '{}'\n\n{}",
+            name,
+            messages.join("\n")
+        )
+        .into());
+    }
+    let image = assembler.to_executable().to_bytes();
+    Ok((image, Some(assembler.symbols)))
+}
+
+fn run(args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(args.value_of("file").unwrap());
+    let trace = args.is_present("trace");
+    let limit = match args.value_of("limit") {
+        Some(limit) => Some(limit.parse::<u64>()?),
+        None => None,
+    };
+
+    let (image, symbols) = load_image(path)?;
+    let mut vm = VM::new();
+    vm.load_executable(&image)?;
+    if args.is_present("profile") {
+        vm.enable_profiling();
+    }
+    if let Some(fuel) = args.value_of("fuel") {
+        vm.set_fuel(Some(fuel.parse()?));
+    }
+    for cost in args.values_of("cost").into_iter().flatten() {
+        let (opcode, amount) = match cost.split_once('=') {
+            Some((opcode, amount)) => (OpCode::from_string(opcode), amount.parse()?),
+            None => return Err(format!("Invalid opcode cost '{}', expected OPCODE=N", cost).into()),
+        };
+        if opcode == OpCode::IGL {
+            return Err(format!("Unknown opcode in '{}'", cost).into());
+        }
+        vm.set_opcode_cost(opcode, amount);
+    }
+    if let Some(trace_file) = args.value_of("trace-file") {
+        let format = args.value_of("trace-format").unwrap().parse::<TraceFormat>()?;
+        let out = io::BufWriter::new(fs::File::create(trace_file)?);
+        vm.start_trace(Box::new(out), format)?;
+    }
+    let outcome = if trace {
+        run_traced(&mut vm, limit)
+    } else {
+        match limit {
+            Some(limit) => vm.run_limited(limit),
+            None => vm.run(),
+        }
     };
+    vm.stop_trace()?;
+    if let Some(profile) = vm.profile() {
+        eprint!("{}", profile.report(vm.read_program(), symbols.as_ref()));
+    }
+    match outcome {
+        RunOutcome::Halted | RunOutcome::EndOfProgram => Ok(()),
+        RunOutcome::Faulted(e) => Err(e.into()),
+        RunOutcome::Stopped(reason) => Err(reason.to_string().into()),
+        RunOutcome::BudgetExhausted => match vm.read_fault() {
+            Some(fault) => Err(fault.clone().into()),
+            None => Err(format!("Instruction limit of {} reached", limit.unwrap_or(0)).into()),
+        },
+    }
+}
+
+/// Steps through the program, printing each executed instruction to stderr.
+fn run_traced(vm: &mut VM, limit: Option<u64>) -> RunOutcome {
+    vm.set_logging(true);
+    let mut executed = 0;
+    loop {
+        if limit.is_some_and(|limit| executed >= limit) {
+            return RunOutcome::BudgetExhausted;
+        }
+        let pc = *vm.read_pc();
+        match vm.step() {
+            Err(e) if e.kind == VmErrorKind::OutOfFuel => return RunOutcome::BudgetExhausted,
+            Ok((done, log)) => {
+                eprintln!("{:04}: {}", pc, log);
+                if done && vm.is_halted() {
+                    return RunOutcome::Halted;
+                } else if done {
+                    return RunOutcome::EndOfProgram;
+                }
+            }
+            Err(e) => return RunOutcome::Faulted(e),
+        }
+        executed += 1;
+    }
+}
+
+fn asm(args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let input = Path::new(args.value_of("input").unwrap());
+    let output = match args.value_of("output") {
+        Some(output) => Path::new(output).to_path_buf(),
+        None => input.with_extension("irb"),
+    };
+    let (image, _) = load_image(input)?;
+    fs::write(&output, image)?;
+    Ok(())
+}
+
+fn disasm(args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(args.value_of("file").unwrap());
+    let executable = Executable::from_bytes(&fs::read(path)?)?;
+    println!("; version {}, entry {}", executable.version, executable.entry);
+    if !executable.ro.is_empty() {
+        println!("; read-only data ({} bytes):", executable.ro.len());
+        let mut offset = 0;
+        for string in executable.ro.split_inclusive(|b| *b == 0) {
+            println!(";   {:04}: {:?}", offset, String::from_utf8_lossy(string));
+            offset += string.len();
+        }
+    }
+    print!("{}", disassemble(&executable.code));
+    Ok(())
+}
+
+fn trace(args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(args.value_of("file").unwrap());
+    for record in read_binary_trace(&fs::read(path)?)? {
+        println!("{}", record);
+    }
+    Ok(())
+}
+
+fn debug(args: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(args.value_of("file").unwrap());
+    let mut vm = VM::new();
+    vm.load_executable(&load_image(path)?.0)?;
+    let mut stub = GdbStub::new(vm);
+    if let Some(socket) = args.value_of("socket") {
+        #[cfg(unix)]
+        {
+            let listener = std::os::unix::net::UnixListener::bind(socket)?;
+            eprintln!("Waiting for GDB on {}", socket);
+            let (mut conn, _) = listener.accept()?;
+            let result = stub.serve(&mut conn);
+            fs::remove_file(socket)?;
+            return Ok(result?);
+        }
+        #[cfg(not(unix))]
+        return Err(format!("Unix socket '{}' is not supported on this platform", socket).into());
+    }
+    let port: u16 = args.value_of("port").unwrap().parse()?;
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for GDB on {}", listener.local_addr()?);
+    let (mut conn, _) = listener.accept()?;
+    // Packets are tiny and strictly request/response, so don't let Nagle batch them
+    conn.set_nodelay(true)?;
+    Ok(stub.serve(&mut conn)?)
 }
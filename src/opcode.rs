@@ -58,10 +58,77 @@ enum_from_primitive! {
         RET,
         JNE,
         JMPL,
+        SYSCALL,
         IGL = 100,
     }
 }
 
+/// Set on the shift-amount byte of SHL/SHR when it holds an immediate count
+/// (the low 7 bits) rather than a register index.
+pub const SHIFT_IMMEDIATE: u8 = 0x80;
+
+/// Number of registers in each of the integer and f64 register banks.
+pub const REGISTER_COUNT: u8 = 32;
+
+/// Access width, in bytes, used by LOADM/SETM when no width operand is given.
+pub const DEFAULT_MEMORY_WIDTH: u8 = 4;
+
+/// The kinds of operand an instruction can be written with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OperandKind {
+    Register,
+    FloatRegister,
+    /// A 16-bit integer immediate
+    Integer,
+    Float,
+    /// A label in the code section
+    Label,
+    /// A label in the data section
+    DataLabel,
+    String,
+}
+
+impl std::fmt::Display for OperandKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OperandKind::Register => write!(f, "register"),
+            OperandKind::FloatRegister => write!(f, "float register"),
+            OperandKind::Integer => write!(f, "integer"),
+            OperandKind::Float => write!(f, "float"),
+            OperandKind::Label => write!(f, "code label"),
+            OperandKind::DataLabel => write!(f, "data label"),
+            OperandKind::String => write!(f, "string"),
+        }
+    }
+}
+
+/// The operands an opcode takes. Each slot lists the kinds it accepts; slots past
+/// `required` may be left out.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub operands: &'static [&'static [OperandKind]],
+    pub required: usize,
+}
+
+impl Signature {
+    const fn exactly(operands: &'static [&'static [OperandKind]]) -> Self {
+        Self {
+            operands,
+            required: operands.len(),
+        }
+    }
+
+    pub fn accepts(&self, count: usize) -> bool {
+        (self.required..=self.operands.len()).contains(&count)
+    }
+}
+
+const REG: &[OperandKind] = &[OperandKind::Register];
+const FREG: &[OperandKind] = &[OperandKind::FloatRegister];
+const INT: &[OperandKind] = &[OperandKind::Integer];
+const ADDRESS: &[OperandKind] = &[OperandKind::Integer, OperandKind::Label];
+const DATA_ADDRESS: &[OperandKind] = &[OperandKind::Integer, OperandKind::DataLabel];
+
 impl OpCode {
     pub fn padded(self) -> String {
         let mut padded: String = self.to_string();
@@ -71,6 +138,37 @@ impl OpCode {
         padded
     }
 
+    /// The operands this opcode is written with, or `None` for reserved opcodes the
+    /// VM doesn't implement yet.
+    pub fn signature(self) -> Option<Signature> {
+        use self::OpCode::*;
+        let operands: &'static [&'static [OperandKind]] = match self {
+            HLT | NOP | RET => &[],
+            JMP | JMPF | JMPB | JEQ | JNE | INC | DEC | ALOC | PUSH | POP => &[REG],
+            EQ | NEQ | GT | LT | GTE | LTE | NOT => &[REG, REG],
+            ADD | SUB | MUL | DIV | AND | OR | XOR => &[REG, REG, REG],
+            SHL | SHR => &[REG, &[OperandKind::Register, OperandKind::Integer], REG],
+            LOAD => &[
+                REG,
+                &[OperandKind::Integer, OperandKind::Label, OperandKind::DataLabel],
+            ],
+            CALL => &[ADDRESS],
+            PRTS => &[DATA_ADDRESS],
+            SYSCALL => &[INT],
+            LOADM | SETM => {
+                return Some(Signature {
+                    operands: &[REG, REG, INT],
+                    required: 2,
+                })
+            }
+            LOADF64 => &[FREG, &[OperandKind::Float, OperandKind::Integer]],
+            EQF64 | NEQF64 | GTF64 | GTEF64 | LTF64 | LTEF64 => &[FREG, FREG],
+            ADDF64 | SUBF64 | MULF64 | DIVF64 => &[FREG, FREG, FREG],
+            DJMPE | LUI | CLOOP | LOOP | JMPL | IGL => return None,
+        };
+        Some(Signature::exactly(operands))
+    }
+
     pub fn from_string(s: &str) -> Self {
         match OpCode::from_str(s) {
             Ok(opcode) => opcode,
@@ -95,10 +193,11 @@ impl From<u8> for OpCode {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// A decoded 4-byte instruction word. It's `Copy` so decoding never allocates.
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction {
     opcode: OpCode,
-    operands: Vec<u8>,
+    operands: [u8; 3],
 }
 
 impl std::fmt::Display for Instruction {
@@ -124,24 +223,32 @@ impl Tou8Vec for Instruction {
     }
 }
 
-impl Into<Vec<u8>> for Instruction {
-    fn into(self) -> Vec<u8> {
-        let mut bytes = vec![self.opcode.into()];
-        bytes.extend(self.operands);
+impl From<Instruction> for Vec<u8> {
+    fn from(val: Instruction) -> Self {
+        let mut bytes = vec![val.opcode.into()];
+        bytes.extend(val.operands);
         bytes
     }
 }
 
 impl Instruction {
-    pub fn new(opcode: OpCode, operands: Vec<u8>) -> Instruction {
+    pub fn new(opcode: OpCode, operands: [u8; 3]) -> Instruction {
         Instruction { opcode, operands }
     }
 
+    /// Decodes the instruction word at the start of `word`.
+    pub fn decode(word: &[u8; 4]) -> Instruction {
+        Instruction {
+            opcode: OpCode::from(word[0]),
+            operands: [word[1], word[2], word[3]],
+        }
+    }
+
     pub fn opcode(&self) -> &OpCode {
         &self.opcode
     }
 
-    pub fn operands(&self) -> &Vec<u8> {
+    pub fn operands(&self) -> &[u8; 3] {
         &self.operands
     }
 }
@@ -153,7 +260,27 @@ mod tests {
 
     #[test]
     fn test_create_instruction() {
-        let instruction = Instruction::new(OpCode::HLT, vec![]);
+        let instruction = Instruction::new(OpCode::HLT, [0; 3]);
         assert_eq!(instruction.opcode, OpCode::HLT);
     }
+
+    #[test]
+    fn test_decode_instruction() {
+        let instruction = Instruction::decode(&[OpCode::ADD as u8, 1, 2, 3]);
+        assert_eq!(instruction, Instruction::new(OpCode::ADD, [1, 2, 3]));
+        assert_eq!(*Instruction::decode(&[0xFF, 0, 0, 0]).opcode(), OpCode::IGL);
+        let bytes: Vec<u8> = instruction.into();
+        assert_eq!(bytes, vec![OpCode::ADD as u8, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_signature() {
+        let add = OpCode::ADD.signature().unwrap();
+        assert!(add.accepts(3));
+        assert!(!add.accepts(2));
+        let loadm = OpCode::LOADM.signature().unwrap();
+        assert!(loadm.accepts(2) && loadm.accepts(3));
+        assert_eq!(loadm.operands[2], &[OperandKind::Integer]);
+        assert_eq!(OpCode::LUI.signature(), None);
+    }
 }
@@ -6,7 +6,7 @@ use std::{
 
 use crate::{
     assembler::{Assembler, AssemblerError},
-    vm::VM,
+    vm::{Breakpoint, RunOutcome, TraceFormat, WatchCondition, VM},
 };
 
 use crossterm::{
@@ -16,8 +16,14 @@ use crossterm::{
     terminal::{Clear, ClearType},
 };
 
+/// Most instructions a single `run` executes when fuel metering is off
+const RUN_BUDGET: u64 = 1_000_000;
+/// Most instructions `back` and `rcontinue` can undo
+const HISTORY_SIZE: usize = 100_000;
+
 struct ShouldExit;
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct REPL {
     command_buffer: Vec<String>,
     assembler: Assembler,
@@ -26,13 +32,17 @@ pub struct REPL {
 
 impl REPL {
     pub fn new() -> Self {
+        let mut vm = VM::new();
+        vm.set_history_limit(HISTORY_SIZE);
+        vm.set_logging(true);
         Self {
             command_buffer: Vec::new(),
             assembler: Assembler::new(),
-            vm: VM::new(),
+            vm,
         }
     }
 
+    #[allow(dead_code)]
     fn assemble_from<'asm>(
         &'asm mut self,
         program: &'asm str,
@@ -76,7 +86,8 @@ impl REPL {
             };
 
             if let Err(errs) = self.assembler.assemble(&("    ".to_owned() + &buffer + "\n")) {
-                Self::log_errors(&mut output, &errs, &buffer);
+                let errs = errs.clone();
+                Self::log_errors(&mut output, &errs, &self.assembler.code, "<repl>");
                 continue
             }
 
@@ -86,12 +97,14 @@ impl REPL {
             } else {
                 self.vm.add_program(&mut self.assembler.last_instruction());
             }
-            let cmd = buffer.drain(..).collect::<String>();
+            self.vm.set_ro_data(self.assembler.ro.clone());
+            let cmd = std::mem::take(&mut buffer);
             self.command_buffer.push("    ".to_string() + &cmd);
         }
     }
 
-    fn log_errors(out: &mut Stdout, errs: &Vec<AssemblerError>, name: &str) {
+    /// Prints each error with the line of `source` it points at.
+    fn log_errors(out: &mut Stdout, errs: &Vec<AssemblerError>, source: &str, name: &str) {
         let name = name.trim_end_matches("\n");
         let mut buff = String::new();
         buff.push_str(&format!(
@@ -99,11 +112,11 @@ impl REPL {
             &name
         ));
         for err in errs {
-            buff.push_str(&format!("- {}\n", err));
+            buff.push_str(&err.render(name, source));
         }
         let msg = format!("Failed to assemble '{}' ", &name);//"Program could not be loaded ";
         buff.push_str(&msg);
-        let x = crossterm::terminal::size().unwrap_or_else(|_| (0, 0)).0;
+        let x = crossterm::terminal::size().unwrap_or((0, 0)).0;
         let len = msg.len() as u16;
         if x > len {
             for _ in 0..x - len {
@@ -126,54 +139,200 @@ impl REPL {
             if *self.vm.read_pc() < self.vm.program_len() {
                 match self.vm.step() {
                     Ok((_, instruction)) => print(&format!("{}\n", instruction)),
-                    Err(e) => println!("Error: {}", e),
+                    Err(e) => print(&format!("Error: {}\n", e)),
                 }
             }
         };
-        match cmd.trim() {
-            "help" => {
+        let args: Vec<&str> = cmd.split_whitespace().collect();
+        match args.as_slice() {
+            ["help"] => {
                 print("Available commands:\n");
                 print("- quit\n");
                 print("- clear\n");
                 print("- run\n");
+                print("- continue\n");
+                print("- break [<address> | @<label>]\n");
+                print("- watch $<register> [<value>]\n");
+                print("- watch heap <address> [<len>]\n");
+                print("- delete <id>\n");
+                print("- fuel [<amount> | off]\n");
                 print("- step\n");
                 print("- [ENTER]\n");
+                print("- back\n");
+                print("- rcontinue\n");
                 print("- reset\n");
                 print("- open <file>\n");
+                print("- export <file>\n");
+                print("- trace [<file> [text | binary] | off]\n");
+                print("- profile [on | off | reset]\n");
+                print("- save <file>\n");
+                print("- load <file>\n");
                 print("- state\n");
                 print("- bytecode\n");
                 print("- reg\n");
                 print("- program\n");
-                return Ok(())
+                Ok(())
             }
-            "quit" => {
+            ["quit"] => {
                 print("Bye!\n");
-                return Err(ShouldExit);
+                Err(ShouldExit)
             }
-            "clear" => {
+            ["clear"] => {
                 execute!(out, Clear(ClearType::All)).unwrap_or_else(|_| {});
-                return Ok(());
+                Ok(())
             }
-            "run" => {
-                self.vm.run();
-                print("Done!\n");
+            ["run"] | ["continue"] => {
+                // Without metering, cap each run so an infinite loop can't hang the REPL
+                let outcome = match self.vm.fuel() {
+                    Some(_) => self.vm.run(),
+                    None => self.vm.run_limited(RUN_BUDGET),
+                };
+                match outcome {
+                    RunOutcome::Halted | RunOutcome::EndOfProgram => print("Done!\n"),
+                    RunOutcome::BudgetExhausted => {
+                        print("Instruction budget exhausted, use run to continue.\n")
+                    }
+                    RunOutcome::Stopped(reason) => {
+                        print(&format!("{}, use continue to resume.\n", reason))
+                    }
+                    outcome => print(&format!("{}\n", outcome)),
+                }
                 self.execute_command("state")
             }
-            "step" => {
+            ["break"] => {
+                for (id, breakpoint) in self.vm.breakpoints() {
+                    print(&format!("{}: {}\n", id, breakpoint));
+                }
+                Ok(())
+            }
+            ["break", target] => {
+                let address = match target.strip_prefix('@') {
+                    Some(label) => self
+                        .assembler
+                        .symbols
+                        .get_symbol_offset(label)
+                        .map(|offset| offset as usize),
+                    None => target.parse::<usize>().ok(),
+                };
+                match address {
+                    Some(address) => {
+                        let id = self.vm.add_breakpoint(Breakpoint::Pc(address));
+                        print(&format!("Breakpoint {} at {:04}\n", id, address));
+                    }
+                    None => print(&format!("Invalid breakpoint address: {}\n", target)),
+                }
+                Ok(())
+            }
+            ["watch", "heap", address, rest @ ..] => {
+                let len = match rest {
+                    [] => Some(1),
+                    [len] => len.parse::<usize>().ok(),
+                    _ => None,
+                };
+                match (address.parse::<usize>().ok(), len) {
+                    (Some(address), Some(len)) if address.checked_add(len).is_none() => {
+                        print(&format!("Heap range {} + {} is out of range\n", address, len));
+                    }
+                    (Some(address), Some(len)) if len > 0 => {
+                        let breakpoint = Breakpoint::Heap { address, len };
+                        let message = breakpoint.to_string();
+                        let id = self.vm.add_breakpoint(breakpoint);
+                        print(&format!("Watchpoint {}: {}\n", id, message));
+                    }
+                    _ => print("Usage: watch heap <address> [<len>]\n"),
+                }
+                Ok(())
+            }
+            ["watch", register, rest @ ..] => {
+                let register = register
+                    .strip_prefix('$')
+                    .and_then(|r| r.parse::<usize>().ok())
+                    .filter(|r| *r < self.vm.read_registers().len());
+                let condition = match rest {
+                    [] => Some(WatchCondition::Change),
+                    [value] | ["==", value] => value.parse().ok().map(WatchCondition::Equals),
+                    _ => None,
+                };
+                match (register, condition) {
+                    (Some(register), Some(condition)) => {
+                        let breakpoint = Breakpoint::Register {
+                            register,
+                            condition,
+                        };
+                        let message = breakpoint.to_string();
+                        let id = self.vm.add_breakpoint(breakpoint);
+                        print(&format!("Watchpoint {}: {}\n", id, message));
+                    }
+                    _ => print("Usage: watch $<register> [<value>]\n"),
+                }
+                Ok(())
+            }
+            ["delete", id] => {
+                match id.parse().ok().and_then(|id| self.vm.remove_breakpoint(id)) {
+                    Some(breakpoint) => print(&format!("Deleted {}: {}\n", id, breakpoint)),
+                    None => print(&format!("No breakpoint or watchpoint {}\n", id)),
+                }
+                Ok(())
+            }
+            ["fuel"] => {
+                match self.vm.fuel() {
+                    Some(fuel) => print(&format!("Fuel: {}\n", fuel)),
+                    None => print("Fuel metering is off.\n"),
+                }
+                Ok(())
+            }
+            ["fuel", "off"] => {
+                self.vm.set_fuel(None);
+                print("Fuel metering is off.\n");
+                Ok(())
+            }
+            ["fuel", amount] => {
+                match amount.parse::<u64>() {
+                    Ok(amount) => {
+                        self.vm.add_fuel(amount);
+                        print(&format!("Fuel: {}\n", self.vm.fuel().unwrap_or(0)));
+                    }
+                    Err(_) => print(&format!("Invalid fuel amount: {}\n", amount)),
+                }
+                Ok(())
+            }
+            ["step"] => {
                 step();
-                return Ok(());
+                Ok(())
             }
-            "" => {
+            [] => {
                 step();
-                return Ok(());
+                Ok(())
+            }
+            ["back"] => {
+                if self.vm.step_back() {
+                    print(&format!(
+                        "Stepped back to {:04} ({} more in history)\n",
+                        self.vm.read_pc(),
+                        self.vm.history_len()
+                    ));
+                } else {
+                    print("No history to step back through.\n");
+                }
+                Ok(())
+            }
+            ["rcontinue"] => {
+                match self.vm.run_back() {
+                    Some(reason) => print(&format!("{}\n", reason)),
+                    None => print(&format!(
+                        "Reached the start of history at {:04}\n",
+                        self.vm.read_pc()
+                    )),
+                }
+                self.execute_command("state")
             }
-            "reset" => {
+            ["reset"] => {
                 self.vm.reset();
                 self.command_buffer.clear();
                 print("Reset complete.");
-                return Ok(());
+                Ok(())
             }
-            "open" => {
+            ["open"] => {
                 print("Please enter the path to the file you wish to load: ");
                 let mut tmp = String::new();
                 if let Err(e) = std::io::stdin().read_line(&mut tmp) {
@@ -188,6 +347,23 @@ impl REPL {
                         return Ok(());
                     }
                 };
+                if filename.extension().is_some_and(|ext| ext == "irb") {
+                    let mut bytes = vec![];
+                    if let Err(e) = f.read_to_end(&mut bytes) {
+                        print(&format!("Unable to read file: {}\n", e));
+                        return Ok(());
+                    }
+                    if let Err(e) = self.vm.load_executable(&bytes) {
+                        print(&format!("Unable to load executable: {}\n", e));
+                        return Ok(());
+                    }
+                    self.command_buffer.clear();
+                    print(&format!(
+                        "loaded {}:\n",
+                        filename.file_name().unwrap().to_str().unwrap()
+                    ));
+                    return Ok(());
+                }
                 let mut contents = String::new();
                 if let Err(e) = f.read_to_string(&mut contents) {
                     print(&format!("Unable to read file: {}\n", e));
@@ -203,7 +379,9 @@ impl REPL {
                 let bytecode = match self.assembler.assemble(&contents) {
                     Ok(bytecode) => bytecode,
                     Err(e) => {
-                        Self::log_errors(&mut out, &e, filename.to_str().unwrap_or(""));
+                        let errs = e.clone();
+                        let name = filename.to_str().unwrap_or("");
+                        Self::log_errors(&mut out, &errs, &self.assembler.code, name);
                         return Ok(());
                     }
                 };
@@ -212,14 +390,126 @@ impl REPL {
                     filename.file_name().unwrap().to_str().unwrap()
                 ));
                 self.vm.add_program(bytecode);
-                self.command_buffer.push(contents.drain(..).collect());
+                self.vm.set_ro_data(self.assembler.ro.clone());
+                self.command_buffer.push(std::mem::take(&mut contents));
+                Ok(())
+            }
+            ["export"] => {
+                print("Please enter the path to write the executable to: ");
+                let mut tmp = String::new();
+                if let Err(e) = std::io::stdin().read_line(&mut tmp) {
+                    print(&format!("Failed to read input: {}", e));
+                }
+                let filename = Path::new(tmp.trim());
+                queue!(std::io::stdout(), MoveToPreviousLine(1)).unwrap_or_else(|_| {});
+                let executable = self.assembler.to_executable();
+                if let Err(e) = std::fs::write(filename, executable.to_bytes()) {
+                    print(&format!("Unable to write file: {}\n", e));
+                    return Ok(());
+                }
+                print(&format!(
+                    "Wrote {} bytes of code and {} bytes of read-only data to {}\n",
+                    executable.code.len(),
+                    executable.ro.len(),
+                    filename.display()
+                ));
+                Ok(())
+            }
+            ["trace"] => {
+                if self.vm.is_tracing() {
+                    print("Tracing is on, use trace off to stop.\n");
+                } else {
+                    print("Tracing is off.\n");
+                }
+                Ok(())
+            }
+            ["trace", "off"] => {
+                match self.vm.stop_trace() {
+                    Ok(_) => print("Tracing stopped.\n"),
+                    Err(e) => print(&format!("Trace was incomplete: {}\n", e)),
+                }
+                Ok(())
+            }
+            ["trace", path, rest @ ..] => {
+                let format = match rest {
+                    [] => Ok(TraceFormat::Text),
+                    [format] => format.parse::<TraceFormat>(),
+                    _ => Err("Usage: trace <file> [text | binary]".to_string()),
+                };
+                let format = match format {
+                    Ok(format) => format,
+                    Err(e) => {
+                        print(&format!("{}\n", e));
+                        return Ok(());
+                    }
+                };
+                let started = File::create(path).and_then(|file| {
+                    self.vm
+                        .start_trace(Box::new(io::BufWriter::new(file)), format)
+                });
+                match started {
+                    Ok(_) => print(&format!("Tracing to {}\n", path)),
+                    Err(e) => print(&format!("Unable to start trace: {}\n", e)),
+                }
+                Ok(())
+            }
+            ["profile"] => {
+                match self.vm.profile() {
+                    Some(profile) => print(&profile.report(
+                        self.vm.read_program(),
+                        Some(&self.assembler.symbols),
+                    )),
+                    None => print("Profiling is off, use profile on to start.\n"),
+                }
+                Ok(())
+            }
+            ["profile", "on"] => {
+                self.vm.enable_profiling();
+                print("Profiling is on.\n");
                 Ok(())
             }
-            "state" => {
+            ["profile", "off"] => {
+                self.vm.disable_profiling();
+                print("Profiling is off.\n");
+                Ok(())
+            }
+            ["profile", "reset"] => {
+                if self.vm.disable_profiling().is_some() {
+                    self.vm.enable_profiling();
+                }
+                print("Profile counts cleared.\n");
+                Ok(())
+            }
+            ["save", path] => {
+                match std::fs::write(path, self.vm.snapshot()) {
+                    Ok(_) => print(&format!("Saved VM state to {}\n", path)),
+                    Err(e) => print(&format!("Unable to write file: {}\n", e)),
+                }
+                Ok(())
+            }
+            ["load", path] => {
+                let bytes = match std::fs::read(path) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        print(&format!("Unable to read file: {}\n", e));
+                        return Ok(());
+                    }
+                };
+                match self.vm.restore(&bytes) {
+                    Ok(_) => {
+                        // The restored program no longer matches the entered source
+                        self.command_buffer.clear();
+                        print(&format!("Restored VM state from {}\n", path));
+                    }
+                    Err(e) => print(&format!("Unable to restore snapshot: {}\n", e)),
+                }
+                Ok(())
+            }
+            ["state"] => {
                 print(&format!("{}", self.vm));
-                return Ok(());
+                Ok(())
             }
-            "bytecode" => {
+            ["bytecode"] => {
                 let mut bytecode_str = "Bytecode:\n".to_owned();
                 let mut buffer = String::new();
                 bytecode_str.push_str("Bytecode:\n");
@@ -236,9 +526,9 @@ impl REPL {
                 }
                 bytecode_str.push_str("End of Bytecode\n");
                 print(&bytecode_str);
-                return Ok(());
+                Ok(())
             }
-            "reg" => {
+            ["reg"] => {
                 println!("Register File: ");
                 let mut buffer = String::from("[ ");
                 for (i, register) in self.vm.read_registers().iter().enumerate() {
@@ -248,10 +538,10 @@ impl REPL {
                     }
                 }
                 buffer.push_str(" ]\nEnd of Register File\n");
-                print(&format!("{}", buffer));
-                return Ok(());
+                print(&buffer.to_string());
+                Ok(())
             }
-            "program" => {
+            ["program"] => {
                 println!("Program:");
                 /*let mut instruction: Vec<String> = Vec::new();
                 //for byte in self.vm.read_program().iter() {
@@ -274,12 +564,34 @@ impl REPL {
                     self.command_buffer.join("\n"),
                     "End of Program\n"
                 ));
-                return Ok(());
+                Ok(())
             }
             _ => {
                 print("Invalid command.\n");
-                return Ok(());
+                Ok(())
             }
         }
     }
 }
+
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tests for repl
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch_heap_rejects_overflowing_range() {
+        let mut repl = REPL::new();
+        let command = format!("watch heap {} 2", usize::MAX);
+        assert!(repl.execute_command(&command).is_ok());
+        assert_eq!(repl.vm.breakpoints().count(), 0);
+        assert!(repl.execute_command("watch heap 4 2").is_ok());
+        assert_eq!(repl.vm.breakpoints().count(), 1);
+    }
+}
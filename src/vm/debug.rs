@@ -0,0 +1,370 @@
+use std::collections::BTreeMap;
+
+use super::VM;
+
+/// A condition that stops `VM::run`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Breakpoint {
+    /// Stop before executing the instruction at this address
+    Pc(usize),
+    /// Stop after an instruction changes the register
+    Register {
+        register: usize,
+        condition: WatchCondition,
+    },
+    /// Stop after an instruction changes any byte in `address..address + len`
+    Heap { address: usize, len: usize },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WatchCondition {
+    /// Any change to the value
+    Change,
+    /// A change that leaves the register holding this value
+    Equals(i32),
+}
+
+/// Why `VM::run` stopped at a breakpoint or watchpoint
+#[derive(Debug, PartialEq, Clone)]
+pub enum StopReason {
+    Breakpoint {
+        id: usize,
+        pc: usize,
+    },
+    Register {
+        id: usize,
+        pc: usize,
+        register: usize,
+        old: i32,
+        new: i32,
+    },
+    Heap {
+        id: usize,
+        pc: usize,
+        address: usize,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+}
+
+/// Breakpoints and watchpoints share one id space so they can be deleted by number.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    next_id: usize,
+    entries: BTreeMap<usize, Breakpoint>,
+    /// Set when `run` stopped at a PC breakpoint, so resuming steps past it
+    pub(super) resume_pc: Option<usize>,
+}
+
+/// A watched value captured before an instruction executes
+pub(super) enum Watched {
+    Register(i32),
+    Heap(Option<Vec<u8>>),
+}
+
+impl std::fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Breakpoint::Pc(pc) => write!(f, "breakpoint at {:04}", pc),
+            Breakpoint::Register {
+                register,
+                condition: WatchCondition::Change,
+            } => write!(f, "watch ${}", register),
+            Breakpoint::Register {
+                register,
+                condition: WatchCondition::Equals(value),
+            } => write!(f, "watch ${} == {}", register, value),
+            Breakpoint::Heap { address, len } => match address.checked_add(*len) {
+                Some(end) => write!(f, "watch heap {}..{}", address, end),
+                None => write!(f, "watch heap {} (+{} bytes)", address, len),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StopReason::Breakpoint { id, pc } => write!(f, "Breakpoint {} at {:04}", id, pc),
+            StopReason::Register {
+                id,
+                pc,
+                register,
+                old,
+                new,
+            } => write!(
+                f,
+                "Watchpoint {}: ${} changed from {} to {} at {:04}",
+                id, register, old, new, pc
+            ),
+            StopReason::Heap {
+                id,
+                pc,
+                address,
+                old,
+                new,
+            } => write!(
+                f,
+                "Watchpoint {}: heap at {} changed from {:02X?} to {:02X?} at {:04}",
+                id, address, old, new, pc
+            ),
+        }
+    }
+}
+
+impl VM {
+    /// Adds a breakpoint or watchpoint and returns its id.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let breakpoints = &mut self.breakpoints;
+        breakpoints.next_id += 1;
+        breakpoints.entries.insert(breakpoints.next_id, breakpoint);
+        breakpoints.next_id
+    }
+
+    /// Removes a breakpoint or watchpoint, returning it if the id existed.
+    pub fn remove_breakpoint(&mut self, id: usize) -> Option<Breakpoint> {
+        self.breakpoints.entries.remove(&id)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints.entries.iter().map(|(id, b)| (*id, b))
+    }
+
+    /// Checked before each instruction `run` executes.
+    pub(super) fn check_pc_breakpoints(&mut self) -> Option<StopReason> {
+        if self.breakpoints.resume_pc.take() == Some(self.pc) {
+            return None;
+        }
+        let pc = self.pc;
+        let id = self
+            .breakpoints
+            .entries
+            .iter()
+            .find(|(_, b)| **b == Breakpoint::Pc(pc))
+            .map(|(id, _)| *id)?;
+        self.breakpoints.resume_pc = Some(pc);
+        Some(StopReason::Breakpoint { id, pc })
+    }
+
+    fn read_watched(&self, breakpoint: &Breakpoint) -> Option<Watched> {
+        match breakpoint {
+            Breakpoint::Pc(_) => None,
+            Breakpoint::Register { register, .. } => Some(Watched::Register(
+                *self.registers.get(*register).unwrap_or(&0),
+            )),
+            Breakpoint::Heap { address, len } => Some(Watched::Heap(
+                address
+                    .checked_add(*len)
+                    .and_then(|end| self.heap.get(*address..end))
+                    .map(|b| b.to_vec()),
+            )),
+        }
+    }
+
+    /// Captures every watched value so `check_watchpoints` can see what an instruction changed.
+    pub(super) fn snapshot_watchpoints(&self) -> Vec<(usize, Watched)> {
+        self.breakpoints
+            .entries
+            .iter()
+            .filter_map(|(id, b)| self.read_watched(b).map(|w| (*id, w)))
+            .collect()
+    }
+
+    pub(super) fn check_watchpoints(
+        &self,
+        pc: usize,
+        before: Vec<(usize, Watched)>,
+    ) -> Option<StopReason> {
+        for (id, old) in before {
+            let breakpoint = &self.breakpoints.entries[&id];
+            match (*breakpoint, old, self.read_watched(breakpoint)) {
+                (
+                    Breakpoint::Register {
+                        register,
+                        condition,
+                    },
+                    Watched::Register(old),
+                    Some(Watched::Register(new)),
+                ) => {
+                    let hit = match condition {
+                        WatchCondition::Change => old != new,
+                        WatchCondition::Equals(value) => old != new && new == value,
+                    };
+                    if hit {
+                        return Some(StopReason::Register {
+                            id,
+                            pc,
+                            register,
+                            old,
+                            new,
+                        });
+                    }
+                }
+                (
+                    Breakpoint::Heap { address, .. },
+                    Watched::Heap(old),
+                    Some(Watched::Heap(new)),
+                ) if old != new => {
+                    return Some(StopReason::Heap {
+                        id,
+                        pc,
+                        address,
+                        old: old.unwrap_or_default(),
+                        new: new.unwrap_or_default(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// Tests for debug
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::OpCode::*;
+    use crate::vm::RunOutcome;
+
+    fn looping_vm() -> VM {
+        let mut vm = VM::new();
+        vm.program = vec![
+            LOAD as u8, 1, 0, 4, // $1 = 4
+            INC as u8, 0, 0, 0, // loop: $0 += 1
+            JMP as u8, 1, 0, 0, // goto loop
+        ];
+        vm
+    }
+
+    #[test]
+    fn test_pc_breakpoint() {
+        let mut vm = looping_vm();
+        let id = vm.add_breakpoint(Breakpoint::Pc(8));
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Stopped(StopReason::Breakpoint { id, pc: 8 })
+        );
+        assert_eq!(vm.registers[0], 1);
+
+        // Continuing runs past the breakpoint and stops on its next hit
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Stopped(StopReason::Breakpoint { id, pc: 8 })
+        );
+        assert_eq!(vm.registers[0], 2);
+
+        assert_eq!(vm.remove_breakpoint(id), Some(Breakpoint::Pc(8)));
+        assert_eq!(vm.run_limited(10), RunOutcome::BudgetExhausted);
+    }
+
+    #[test]
+    fn test_register_watchpoint() {
+        let mut vm = looping_vm();
+        let id = vm.add_breakpoint(Breakpoint::Register {
+            register: 0,
+            condition: WatchCondition::Change,
+        });
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Stopped(StopReason::Register {
+                id,
+                pc: 4,
+                register: 0,
+                old: 0,
+                new: 1
+            })
+        );
+        // Stopped after the instruction that made the change
+        assert_eq!(vm.pc, 8);
+    }
+
+    #[test]
+    fn test_register_watchpoint_value() {
+        let mut vm = looping_vm();
+        let id = vm.add_breakpoint(Breakpoint::Register {
+            register: 0,
+            condition: WatchCondition::Equals(5),
+        });
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Stopped(StopReason::Register {
+                id,
+                pc: 4,
+                register: 0,
+                old: 4,
+                new: 5
+            })
+        );
+        assert_eq!(vm.registers[0], 5);
+    }
+
+    #[test]
+    fn test_heap_watchpoint() {
+        let mut vm = VM::new();
+        vm.program = vec![
+            LOAD as u8, 0, 0, 8, //
+            ALOC as u8, 0, 0, 0, // Heap grows to 8 bytes
+            LOAD as u8, 1, 0, 2, //
+            SETM as u8, 1, 1, 1, // heap[2] = 2
+            SETM as u8, 1, 1, 1, // No change
+            HLT as u8, 0, 0, 0,
+        ];
+        let id = vm.add_breakpoint(Breakpoint::Heap { address: 2, len: 2 });
+        // Allocation brings the watched range into existence
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Stopped(StopReason::Heap {
+                id,
+                pc: 4,
+                address: 2,
+                old: vec![],
+                new: vec![0, 0]
+            })
+        );
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Stopped(StopReason::Heap {
+                id,
+                pc: 12,
+                address: 2,
+                old: vec![0, 0],
+                new: vec![2, 0]
+            })
+        );
+        assert_eq!(vm.run(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn test_heap_watchpoint_overflowing_range() {
+        let mut vm = VM::new();
+        vm.program = vec![
+            LOAD as u8, 0, 0, 8, //
+            ALOC as u8, 0, 0, 0, //
+            HLT as u8, 0, 0, 0,
+        ];
+        let breakpoint = Breakpoint::Heap {
+            address: usize::MAX,
+            len: 2,
+        };
+        assert_eq!(
+            breakpoint.to_string(),
+            format!("watch heap {} (+2 bytes)", usize::MAX)
+        );
+        // The range can never exist, so the watchpoint never fires
+        vm.add_breakpoint(breakpoint);
+        assert_eq!(vm.run(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn test_breakpoint_ids() {
+        let mut vm = VM::new();
+        let a = vm.add_breakpoint(Breakpoint::Pc(0));
+        let b = vm.add_breakpoint(Breakpoint::Heap { address: 0, len: 4 });
+        assert_ne!(a, b);
+        assert_eq!(vm.remove_breakpoint(a), Some(Breakpoint::Pc(0)));
+        assert_eq!(vm.remove_breakpoint(a), None);
+        let ids: Vec<usize> = vm.breakpoints().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![b]);
+    }
+}
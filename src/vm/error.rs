@@ -0,0 +1,103 @@
+use crate::opcode::OpCode;
+
+/// A fault raised while executing an instruction
+#[derive(Debug, PartialEq, Clone)]
+pub struct VmError {
+    /// Address of the faulting instruction
+    pub pc: usize,
+    /// The decoded opcode, if decoding got that far
+    pub opcode: Option<OpCode>,
+    pub kind: VmErrorKind,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmErrorKind {
+    IllegalOpcode { byte: u8 },
+    RegisterOutOfBounds { index: usize },
+    FloatRegisterOutOfBounds { index: usize },
+    DivideByZero,
+    TruncatedInstruction,
+    InvalidJump { target: i64 },
+    HeapOutOfBounds { address: i32, width: usize, len: usize },
+    InvalidMemoryWidth { width: u8 },
+    InvalidAllocation { bytes: i32 },
+    StackOverflow,
+    StackUnderflow,
+    CallStackOverflow,
+    CallStackUnderflow,
+    ReadOnlyOutOfBounds { offset: usize, len: usize },
+    UnterminatedString { offset: usize },
+    InvalidString { offset: usize },
+    OutOfFuel,
+    UnknownSyscall { number: u16 },
+}
+
+impl VmError {
+    pub fn new(pc: usize, opcode: Option<OpCode>, kind: VmErrorKind) -> Self {
+        Self { pc, opcode, kind }
+    }
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.opcode {
+            Some(opcode) => write!(f, "{} at pc {:04} ({})", self.kind, self.pc, opcode),
+            None => write!(f, "{} at pc {:04}", self.kind, self.pc),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+impl std::fmt::Display for VmErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VmErrorKind::IllegalOpcode { byte } => write!(f, "Illegal opcode {:#04X}", byte),
+            VmErrorKind::RegisterOutOfBounds { index } => {
+                write!(f, "Register index {} out of bounds", index)
+            }
+            VmErrorKind::FloatRegisterOutOfBounds { index } => {
+                write!(f, "Float register index {} out of bounds", index)
+            }
+            VmErrorKind::DivideByZero => write!(f, "Division by zero"),
+            VmErrorKind::TruncatedInstruction => {
+                write!(f, "Instruction runs past the end of the program")
+            }
+            VmErrorKind::InvalidJump { target } => write!(f, "Invalid jump target {}", target),
+            VmErrorKind::HeapOutOfBounds {
+                address,
+                width,
+                len,
+            } => {
+                write!(
+                    f,
+                    "Heap access of {} bytes at {} out of bounds (heap len: {})",
+                    width, address, len
+                )
+            }
+            VmErrorKind::InvalidMemoryWidth { width } => {
+                write!(f, "Invalid memory access width {}", width)
+            }
+            VmErrorKind::InvalidAllocation { bytes } => {
+                write!(f, "Invalid allocation of {} bytes", bytes)
+            }
+            VmErrorKind::StackOverflow => write!(f, "Stack overflow"),
+            VmErrorKind::StackUnderflow => write!(f, "Stack underflow"),
+            VmErrorKind::CallStackOverflow => write!(f, "Call stack overflow"),
+            VmErrorKind::CallStackUnderflow => write!(f, "Call stack underflow"),
+            VmErrorKind::ReadOnlyOutOfBounds { offset, len } => {
+                write!(f, "Read-only offset {} out of bounds (len: {})", offset, len)
+            }
+            VmErrorKind::UnterminatedString { offset } => {
+                write!(f, "Unterminated string at read-only offset {}", offset)
+            }
+            VmErrorKind::InvalidString { offset } => {
+                write!(f, "Invalid UTF-8 string at read-only offset {}", offset)
+            }
+            VmErrorKind::OutOfFuel => write!(f, "Out of fuel"),
+            VmErrorKind::UnknownSyscall { number } => {
+                write!(f, "No host function registered for syscall {}", number)
+            }
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::VecDeque;
+
+use super::{StopReason, VM};
+use crate::opcode::{OpCode, OpCode::*};
+
+/// A bounded undo log of executed instructions, oldest first.
+/// Recording is off while the limit is 0.
+#[derive(Debug, Default)]
+pub struct History {
+    entries: VecDeque<UndoEntry>,
+    pub(super) limit: usize,
+}
+
+/// The state one instruction overwrote, enough to put the VM back as it was before it ran
+#[derive(Debug)]
+struct UndoEntry {
+    pc: usize,
+    /// Changed registers and their previous values
+    registers: Vec<(usize, i32)>,
+    float_registers: Vec<(usize, f64)>,
+    remainder: u32,
+    equal_flag: bool,
+    halted: bool,
+    fuel: Option<u64>,
+    heap_len: usize,
+    /// Heap bytes the instruction could overwrite or free, and where they started
+    heap: Option<(usize, Vec<u8>)>,
+    stack_len: usize,
+    stack_top: Option<i32>,
+    call_stack_len: usize,
+    call_stack_top: Option<usize>,
+}
+
+/// An undo entry under construction, with the registers as they were before the step
+pub(super) struct PendingUndo {
+    entry: UndoEntry,
+    registers: [i32; 32],
+    float_registers: [f64; 32],
+}
+
+impl VM {
+    /// Number of executed instructions that can be stepped back over
+    pub fn history_len(&self) -> usize {
+        self.history.entries.len()
+    }
+
+    /// Sets how many instructions `step_back` can undo. A limit of 0 turns recording off.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history.limit = limit;
+        while self.history.entries.len() > limit {
+            self.history.entries.pop_front();
+        }
+    }
+
+    pub(super) fn clear_history(&mut self) {
+        self.history.entries.clear();
+    }
+
+    /// Heap bytes the instruction at `pc` could overwrite (SETM, SYSCALL) or free (ALOC),
+    /// worked out before it runs.
+    fn heap_at_risk(&self) -> Option<(usize, Vec<u8>)> {
+        let word = self.program.get(self.pc..self.pc + 4)?;
+        let range = match OpCode::from(word[0]) {
+            SETM => self.pending_heap_write()?,
+            ALOC => {
+                let bytes = *self.registers.get(word[1] as usize).ok()?;
+                let start = self.heap.len() as i64 + bytes as i64;
+                if bytes >= 0 || start < 0 {
+                    return None;
+                }
+                start as usize..self.heap.len()
+            }
+            // A host function may write anywhere in the heap
+            SYSCALL => 0..self.heap.len(),
+            _ => return None,
+        };
+        Some((range.start, self.heap[range].to_vec()))
+    }
+
+    /// Captures the state the next instruction may change. Registers are copied in
+    /// full here and trimmed to the ones that changed by `record_undo`.
+    pub(super) fn begin_undo(&self) -> Option<PendingUndo> {
+        if self.history.limit == 0 || self.pc >= self.program.len() {
+            return None;
+        }
+        let entry = UndoEntry {
+            pc: self.pc,
+            registers: vec![],
+            float_registers: vec![],
+            remainder: self.remainder,
+            equal_flag: self.equal_flag,
+            halted: self.halted,
+            fuel: self.fuel,
+            heap_len: self.heap.len(),
+            heap: self.heap_at_risk(),
+            stack_len: self.stack.len(),
+            stack_top: self.stack.last().copied(),
+            call_stack_len: self.call_stack.len(),
+            call_stack_top: self.call_stack.last().copied(),
+        };
+        Some(PendingUndo {
+            entry,
+            registers: self.registers.registers,
+            float_registers: self.float_registers.registers,
+        })
+    }
+
+    pub(super) fn record_undo(&mut self, undo: PendingUndo) {
+        let PendingUndo {
+            mut entry,
+            registers,
+            float_registers,
+        } = undo;
+        entry.registers = (0..registers.len())
+            .filter(|i| registers[*i] != self.registers.registers[*i])
+            .map(|i| (i, registers[i]))
+            .collect();
+        entry.float_registers = (0..float_registers.len())
+            // Compare bits so NaN results still count as unchanged
+            .filter(|i| {
+                float_registers[*i].to_bits() != self.float_registers.registers[*i].to_bits()
+            })
+            .map(|i| (i, float_registers[i]))
+            .collect();
+        if self.history.entries.len() >= self.history.limit {
+            self.history.entries.pop_front();
+        }
+        self.history.entries.push_back(entry);
+    }
+
+    /// Undoes the most recently executed instruction. Returns false if there is no history.
+    pub fn step_back(&mut self) -> bool {
+        let entry = match self.history.entries.pop_back() {
+            Some(entry) => entry,
+            None => return false,
+        };
+        self.pc = entry.pc;
+        for (index, value) in entry.registers {
+            self.registers.registers[index] = value;
+        }
+        for (index, value) in entry.float_registers {
+            self.float_registers.registers[index] = value;
+        }
+        self.remainder = entry.remainder;
+        self.equal_flag = entry.equal_flag;
+        self.halted = entry.halted;
+        self.fuel = entry.fuel;
+        self.heap.resize(entry.heap_len, 0);
+        if let Some((start, bytes)) = entry.heap {
+            self.heap[start..start + bytes.len()].copy_from_slice(&bytes);
+        }
+        // An instruction pushes or pops at most one value on each stack
+        if self.stack.len() > entry.stack_len {
+            self.stack.truncate(entry.stack_len);
+        } else if self.stack.len() < entry.stack_len {
+            self.stack.extend(entry.stack_top);
+        }
+        if self.call_stack.len() > entry.call_stack_len {
+            self.call_stack.truncate(entry.call_stack_len);
+        } else if self.call_stack.len() < entry.call_stack_len {
+            self.call_stack.extend(entry.call_stack_top);
+        }
+        self.fault = None;
+        self.breakpoints.resume_pc = None;
+        true
+    }
+
+    /// Steps backwards until a breakpoint or watchpoint is hit, or history runs out.
+    /// Running forwards afterwards resumes past a breakpoint it stopped at.
+    pub fn run_back(&mut self) -> Option<StopReason> {
+        loop {
+            let watched = self.snapshot_watchpoints();
+            if !self.step_back() {
+                return None;
+            }
+            if let Some(reason) = self.check_watchpoints(self.pc, watched) {
+                return Some(reason);
+            }
+            if let Some(reason) = self.check_pc_breakpoints() {
+                return Some(reason);
+            }
+        }
+    }
+}
+
+/// Tests for history
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Breakpoint, RunOutcome};
+
+    fn recording_vm(program: Vec<u8>) -> VM {
+        let mut vm = VM::new();
+        vm.set_history_limit(100);
+        vm.program = program;
+        vm
+    }
+
+    #[test]
+    fn test_step_back_registers() {
+        let mut vm = recording_vm(vec![
+            LOAD as u8, 0, 0, 7, //
+            LOAD as u8, 1, 0, 2, //
+            DIV as u8, 0, 1, 2, //
+            EQ as u8, 2, 2, 0,
+        ]);
+        assert_eq!(vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!((vm.registers[2], vm.remainder, vm.equal_flag), (3, 1, true));
+        assert_eq!(vm.history_len(), 4);
+
+        assert!(vm.step_back());
+        assert!(!vm.equal_flag);
+        assert!(vm.step_back());
+        assert_eq!((vm.registers[2], vm.remainder, vm.pc), (0, 0, 8));
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(vm.registers.registers, [0; 32]);
+        assert_eq!(vm.pc, 0);
+        assert!(!vm.step_back());
+    }
+
+    #[test]
+    fn test_step_back_heap_and_stacks() {
+        let mut vm = recording_vm(vec![
+            LOAD as u8, 0, 0, 4, //
+            ALOC as u8, 0, 0, 0, //
+            LOAD as u8, 1, 0, 0x7F, //
+            SETM as u8, 2, 1, 1, // heap[0] = 0x7F
+            PUSH as u8, 1, 0, 0, //
+            POP as u8, 3, 0, 0, //
+            CALL as u8, 0, 28, 0, //
+            HLT as u8, 0, 0, 0,
+        ]);
+        assert_eq!(vm.run(), RunOutcome::Halted);
+        assert_eq!(vm.heap, vec![0x7F, 0, 0, 0]);
+
+        assert!(vm.step_back()); // HLT
+        assert!(!vm.halted);
+        assert!(vm.step_back()); // CALL
+        assert_eq!(vm.call_stack, Vec::<usize>::new());
+        assert!(vm.step_back()); // POP
+        assert_eq!((vm.stack.clone(), vm.registers[3]), (vec![0x7F], 0));
+        assert!(vm.step_back()); // PUSH
+        assert!(vm.stack.is_empty());
+        assert!(vm.step_back()); // SETM
+        assert_eq!(vm.heap, vec![0; 4]);
+        assert!(vm.step_back()); // LOAD
+        assert!(vm.step_back()); // ALOC
+        assert!(vm.heap.is_empty());
+
+        // Replaying gives the same result
+        assert_eq!(vm.run(), RunOutcome::Halted);
+        assert_eq!(vm.heap, vec![0x7F, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_step_back_heap_free() {
+        let mut vm = recording_vm(vec![ALOC as u8, 0, 0, 0]);
+        vm.heap = vec![1, 2, 3, 4];
+        vm.registers[0] = -2;
+        assert_eq!(vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(vm.heap, vec![1, 2]);
+        vm.step_back();
+        assert_eq!(vm.heap, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let mut vm = recording_vm(vec![INC as u8, 0, 0, 0, JMP as u8, 1, 0, 0]);
+        vm.set_history_limit(3);
+        assert_eq!(vm.run_limited(10), RunOutcome::BudgetExhausted);
+        assert_eq!(vm.history_len(), 3);
+        while vm.step_back() {}
+        assert_eq!(vm.registers[0], 4);
+
+        vm.set_history_limit(0);
+        vm.run_limited(10);
+        assert_eq!(vm.history_len(), 0);
+    }
+
+    #[test]
+    fn test_run_back() {
+        let mut vm = recording_vm(vec![
+            LOAD as u8, 1, 0, 4, //
+            INC as u8, 0, 0, 0, //
+            JMP as u8, 1, 0, 0,
+        ]);
+        assert_eq!(vm.run_limited(9), RunOutcome::BudgetExhausted);
+        assert_eq!(vm.registers[0], 4);
+
+        let id = vm.add_breakpoint(Breakpoint::Pc(4));
+        assert_eq!(vm.run_back(), Some(StopReason::Breakpoint { id, pc: 4 }));
+        assert_eq!(vm.registers[0], 3);
+        // Running forward executes the instruction at the breakpoint
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Stopped(StopReason::Breakpoint { id, pc: 4 })
+        );
+        assert_eq!(vm.registers[0], 4);
+
+        vm.remove_breakpoint(id);
+        assert_eq!(vm.run_back(), None);
+        assert_eq!((vm.pc, vm.registers[0], vm.registers[1]), (0, 0, 0));
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use super::{VmErrorKind, VM};
+
+/// Host function that prints the string at read-only offset `args[0]`. PRTS calls it too.
+pub const SYS_PRINT_STRING: u16 = 0;
+/// Host function that prints `args[0]` as a decimal integer
+pub const SYS_PRINT_INT: u16 = 1;
+
+/// Number of argument registers copied into a host call
+pub const HOST_ARGS: usize = 4;
+
+/// A function the embedding application exposes to guest code through SYSCALL.
+/// Returning `Ok(Some(value))` stores `value` in `$0`, and an error faults the VM,
+/// undoing any changes the function made to the registers and heap.
+pub type HostFunction = Box<dyn FnMut(&mut HostContext) -> Result<Option<i32>, VmErrorKind>>;
+
+/// The machine state a host function may read and change
+pub struct HostContext<'vm> {
+    /// `$0` to `$3` as they were when SYSCALL ran, or the string offset for PRTS
+    pub args: [i32; HOST_ARGS],
+    pub registers: &'vm mut [i32; 32],
+    pub float_registers: &'vm mut [f64; 32],
+    pub heap: &'vm mut Vec<u8>,
+    pub ro_data: &'vm [u8],
+}
+
+impl HostContext<'_> {
+    /// Reads the null-terminated string starting at `offset` in the read-only segment.
+    pub fn read_ro_string(&self, offset: usize) -> Result<&str, VmErrorKind> {
+        read_ro_string(self.ro_data, offset)
+    }
+}
+
+pub(super) fn read_ro_string(ro_data: &[u8], offset: usize) -> Result<&str, VmErrorKind> {
+    let bytes = match ro_data.get(offset..) {
+        Some(bytes) if !bytes.is_empty() => bytes,
+        _ => {
+            return Err(VmErrorKind::ReadOnlyOutOfBounds {
+                offset,
+                len: ro_data.len(),
+            })
+        }
+    };
+    let end = match bytes.iter().position(|b| *b == 0) {
+        Some(end) => end,
+        None => return Err(VmErrorKind::UnterminatedString { offset }),
+    };
+    std::str::from_utf8(&bytes[..end]).map_err(|_| VmErrorKind::InvalidString { offset })
+}
+
+/// Host functions by number, starting out with the built-in I/O
+pub struct HostFunctions {
+    functions: HashMap<u16, HostFunction>,
+}
+
+impl Default for HostFunctions {
+    fn default() -> Self {
+        let mut functions: HashMap<u16, HostFunction> = HashMap::new();
+        functions.insert(
+            SYS_PRINT_STRING,
+            Box::new(|ctx| {
+                print!("{}", ctx.read_ro_string(ctx.args[0] as u32 as usize)?);
+                Ok(None)
+            }),
+        );
+        functions.insert(
+            SYS_PRINT_INT,
+            Box::new(|ctx| {
+                print!("{}", ctx.args[0]);
+                Ok(None)
+            }),
+        );
+        Self { functions }
+    }
+}
+
+impl std::fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut numbers: Vec<&u16> = self.functions.keys().collect();
+        numbers.sort();
+        f.debug_set().entries(numbers).finish()
+    }
+}
+
+impl VM {
+    /// Makes `function` callable from guest code as `syscall #number`, replacing any
+    /// function already registered under that number, built-ins included.
+    pub fn register_host_function<F>(&mut self, number: u16, function: F)
+    where
+        F: FnMut(&mut HostContext) -> Result<Option<i32>, VmErrorKind> + 'static,
+    {
+        self.host_functions
+            .functions
+            .insert(number, Box::new(function));
+    }
+
+    pub fn unregister_host_function(&mut self, number: u16) -> Option<HostFunction> {
+        self.host_functions.functions.remove(&number)
+    }
+
+    /// Runs host function `number`, returning its result without touching `$0`.
+    /// The registers and heap are put back as they were if the function fails.
+    pub(super) fn call_host(
+        &mut self,
+        number: u16,
+        args: [i32; HOST_ARGS],
+    ) -> Result<Option<i32>, VmErrorKind> {
+        let function = match self.host_functions.functions.get_mut(&number) {
+            Some(function) => function,
+            None => return Err(VmErrorKind::UnknownSyscall { number }),
+        };
+        let registers = self.registers.registers;
+        let float_registers = self.float_registers.registers;
+        let heap = self.heap.clone();
+        let mut ctx = HostContext {
+            args,
+            registers: &mut self.registers.registers,
+            float_registers: &mut self.float_registers.registers,
+            heap: &mut self.heap,
+            ro_data: &self.ro_data,
+        };
+        let result = function(&mut ctx);
+        if result.is_err() {
+            self.registers.registers = registers;
+            self.float_registers.registers = float_registers;
+            self.heap = heap;
+        }
+        result
+    }
+
+    /// Handles `syscall #number`: arguments come from `$0` to `$3` and a returned value
+    /// goes back in `$0`.
+    pub(super) fn syscall(&mut self, number: u16) -> Result<(), VmErrorKind> {
+        let mut args = [0; HOST_ARGS];
+        args.copy_from_slice(&self.registers.registers[..HOST_ARGS]);
+        if let Some(value) = self.call_host(number, args)? {
+            self.registers.registers[0] = value;
+        }
+        Ok(())
+    }
+}
+
+/// Tests for host
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::OpCode::*;
+    use crate::vm::RunOutcome;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_syscall_args_and_result() {
+        let mut vm = VM::new();
+        vm.register_host_function(7, |ctx| Ok(Some(ctx.args[0] * ctx.args[1])));
+        vm.program = vec![
+            LOAD as u8, 0, 0, 6, //
+            LOAD as u8, 1, 0, 7, //
+            SYSCALL as u8, 0, 7, 0,
+        ];
+        assert_eq!(vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(vm.registers[0], 42);
+        assert_eq!(vm.registers[1], 7);
+    }
+
+    #[test]
+    fn test_syscall_heap_access() {
+        let mut vm = VM::new();
+        vm.register_host_function(1000, |ctx| {
+            let address = ctx.args[0] as usize;
+            match ctx.heap.get_mut(address) {
+                Some(byte) => *byte = 0xAB,
+                None => {
+                    return Err(VmErrorKind::HeapOutOfBounds {
+                        address: ctx.args[0],
+                        width: 1,
+                        len: ctx.heap.len(),
+                    })
+                }
+            }
+            Ok(None)
+        });
+        vm.heap = vec![0; 4];
+        vm.registers[0] = 2;
+        vm.program = vec![SYSCALL as u8, 0x03, 0xE8, 0];
+        assert_eq!(vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(vm.heap, vec![0, 0, 0xAB, 0]);
+        // Nothing to return, so $0 is left alone
+        assert_eq!(vm.registers[0], 2);
+
+        vm.pc = 0;
+        vm.registers[0] = 9;
+        match vm.run() {
+            RunOutcome::Faulted(e) => {
+                assert_eq!(e.pc, 0);
+                assert_eq!(
+                    e.kind,
+                    VmErrorKind::HeapOutOfBounds {
+                        address: 9,
+                        width: 1,
+                        len: 4
+                    }
+                );
+            }
+            outcome => panic!("Expected a fault, got {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_failed_syscall_leaves_state_alone() {
+        let mut vm = VM::new();
+        vm.register_host_function(3, |ctx| {
+            ctx.heap[0] = 0xFF;
+            ctx.heap.push(1);
+            ctx.registers[5] = 5;
+            ctx.float_registers[2] = 2.5;
+            Err(VmErrorKind::UnknownSyscall { number: 3 })
+        });
+        vm.heap = vec![1, 2];
+        vm.program = vec![SYSCALL as u8, 0, 3, 0];
+        assert!(matches!(vm.run(), RunOutcome::Faulted(_)));
+        assert_eq!(vm.heap, vec![1, 2]);
+        assert_eq!(vm.registers[5], 0);
+        assert_eq!(vm.float_registers[2], 0.0);
+        assert_eq!(*vm.read_pc(), 0);
+    }
+
+    #[test]
+    fn test_unknown_syscall() {
+        let mut vm = VM::new();
+        vm.program = vec![SYSCALL as u8, 0, 42, 0];
+        match vm.run() {
+            RunOutcome::Faulted(e) => {
+                assert_eq!(e.kind, VmErrorKind::UnknownSyscall { number: 42 })
+            }
+            outcome => panic!("Expected a fault, got {:?}", outcome),
+        }
+        assert!(vm.unregister_host_function(SYS_PRINT_INT).is_some());
+        assert!(vm.unregister_host_function(SYS_PRINT_INT).is_none());
+    }
+
+    #[test]
+    fn test_prts_uses_print_host_function() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let captured = output.clone();
+        let mut vm = VM::new();
+        vm.register_host_function(SYS_PRINT_STRING, move |ctx| {
+            let text = ctx.read_ro_string(ctx.args[0] as usize)?.to_string();
+            captured.borrow_mut().push_str(&text);
+            Ok(Some(-1))
+        });
+        vm.set_ro_data(b"Hello\0World\0".to_vec());
+        vm.registers[0] = 6;
+        vm.program = vec![
+            PRTS as u8, 0, 0, 0, //
+            SYSCALL as u8, 0, 0, 0,
+        ];
+        assert_eq!(vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(*output.borrow(), "HelloWorld");
+        // Only SYSCALL writes the result back
+        assert_eq!(vm.registers[0], -1);
+    }
+
+    #[test]
+    fn test_step_back_over_syscall() {
+        let mut vm = VM::new();
+        vm.set_history_limit(10);
+        vm.register_host_function(5, |ctx| {
+            ctx.heap.iter_mut().for_each(|b| *b += 1);
+            ctx.registers[4] = 99;
+            Ok(Some(1))
+        });
+        vm.heap = vec![1, 2, 3];
+        vm.program = vec![SYSCALL as u8, 0, 5, 0];
+        vm.run();
+        assert_eq!((vm.heap.clone(), vm.registers[4]), (vec![2, 3, 4], 99));
+        assert!(vm.step_back());
+        assert_eq!(vm.heap, vec![1, 2, 3]);
+        assert_eq!((vm.registers[0], vm.registers[4]), (0, 0));
+    }
+}
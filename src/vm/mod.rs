@@ -0,0 +1,1764 @@
+use std::ops::{Index, IndexMut, Range};
+
+mod debug;
+mod error;
+mod history;
+mod host;
+mod profile;
+mod snapshot;
+mod trace;
+use debug::Breakpoints;
+use history::History;
+use host::HostFunctions;
+use trace::Tracer;
+pub use debug::{Breakpoint, StopReason, WatchCondition};
+pub use error::{VmError, VmErrorKind};
+pub use host::{HostContext, HostFunction, HOST_ARGS, SYS_PRINT_INT, SYS_PRINT_STRING};
+pub use profile::{BranchCounts, Profile};
+pub use snapshot::{SnapshotError, SNAPSHOT_MAGIC, SNAPSHOT_VERSION};
+pub use trace::{
+    read_binary_trace, TraceError, TraceFormat, TraceRecord, TRACE_MAGIC, TRACE_VERSION,
+};
+
+use crate::executable::{Executable, ExecutableError};
+use crate::opcode::{Instruction, OpCode, OpCode::*, DEFAULT_MEMORY_WIDTH, SHIFT_IMMEDIATE};
+
+#[derive(Debug)]
+pub struct RegisterSet {
+    pub registers: [i32; 32],
+}
+
+impl RegisterSet {
+    pub fn new() -> Self {
+        RegisterSet { registers: [0; 32] }
+    }
+
+    pub fn get(&self, index: usize) -> Result<&i32, VmErrorKind> {
+        if index < self.registers.len() {
+            Ok(&self.registers[index])
+        } else {
+            Err(VmErrorKind::RegisterOutOfBounds { index })
+        }
+    }
+
+    pub fn set(&mut self, index: usize, value: i32) -> Result<(), VmErrorKind> {
+        if index < self.registers.len() {
+            self.registers[index] = value;
+            Ok(())
+        } else {
+            Err(VmErrorKind::RegisterOutOfBounds { index })
+        }
+    }
+
+    pub fn inc(&mut self, index: usize) -> Result<(), VmErrorKind> {
+        if index < self.registers.len() {
+            self.registers[index] = self.registers[index].wrapping_add(1);
+            Ok(())
+        } else {
+            Err(VmErrorKind::RegisterOutOfBounds { index })
+        }
+    }
+
+    pub fn dec(&mut self, index: usize) -> Result<(), VmErrorKind> {
+        if index < self.registers.len() {
+            self.registers[index] = self.registers[index].wrapping_sub(1);
+            Ok(())
+        } else {
+            Err(VmErrorKind::RegisterOutOfBounds { index })
+        }
+    }
+}
+
+impl Default for RegisterSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index<usize> for RegisterSet {
+    type Output = i32;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.registers[index]
+    }
+}
+
+impl IndexMut<usize> for RegisterSet {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.registers[index]
+    }
+}
+
+#[derive(Debug)]
+pub struct FloatRegisterSet {
+    pub registers: [f64; 32],
+}
+
+impl FloatRegisterSet {
+    pub fn new() -> Self {
+        FloatRegisterSet {
+            registers: [0.0; 32],
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Result<&f64, VmErrorKind> {
+        if index < self.registers.len() {
+            Ok(&self.registers[index])
+        } else {
+            Err(VmErrorKind::FloatRegisterOutOfBounds { index })
+        }
+    }
+
+    pub fn set(&mut self, index: usize, value: f64) -> Result<(), VmErrorKind> {
+        if index < self.registers.len() {
+            self.registers[index] = value;
+            Ok(())
+        } else {
+            Err(VmErrorKind::FloatRegisterOutOfBounds { index })
+        }
+    }
+}
+
+impl Default for FloatRegisterSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index<usize> for FloatRegisterSet {
+    type Output = f64;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.registers[index]
+    }
+}
+
+impl IndexMut<usize> for FloatRegisterSet {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.registers[index]
+    }
+}
+
+/// Maximum number of values the PUSH/POP stack can hold.
+pub const STACK_SIZE: usize = 1024;
+/// Maximum call depth for CALL/RET.
+pub const CALL_STACK_SIZE: usize = 256;
+/// Fuel charged for an instruction whose opcode has no explicit cost.
+pub const DEFAULT_OPCODE_COST: u64 = 1;
+
+/// Why a call to `VM::run` returned
+#[derive(Debug, PartialEq, Clone)]
+pub enum RunOutcome {
+    /// A HLT instruction was executed
+    Halted,
+    /// An instruction faulted. The VM is left as it was before that instruction,
+    /// with the program counter pointing at it.
+    Faulted(VmError),
+    /// Execution ran off the end of the program
+    EndOfProgram,
+    /// The instruction budget was used up before the program stopped
+    BudgetExhausted,
+    /// A breakpoint or watchpoint was hit. Running again resumes from here.
+    Stopped(StopReason),
+}
+
+impl std::fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RunOutcome::Halted => write!(f, "Halted"),
+            RunOutcome::Faulted(e) => write!(f, "Faulted: {}", e),
+            RunOutcome::EndOfProgram => write!(f, "Reached end of program"),
+            RunOutcome::BudgetExhausted => write!(f, "Instruction budget exhausted"),
+            RunOutcome::Stopped(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VM {
+    registers: RegisterSet,
+    float_registers: FloatRegisterSet,
+    pc: usize,
+    program: Vec<u8>,
+    ro_data: Vec<u8>,
+    heap: Vec<u8>,
+    stack: Vec<i32>,
+    call_stack: Vec<usize>,
+    remainder: u32,
+    equal_flag: bool,
+    halted: bool,
+    fault: Option<VmError>,
+    /// Remaining fuel, or `None` for unmetered execution
+    fuel: Option<u64>,
+    /// Fuel charged per instruction, indexed by opcode byte
+    opcode_costs: [u64; 256],
+    breakpoints: Breakpoints,
+    history: History,
+    tracer: Option<Tracer>,
+    profile: Option<Profile>,
+    host_functions: HostFunctions,
+    /// Whether `step` describes each instruction it executes
+    logging: bool,
+}
+
+impl VM {
+    pub fn new() -> Self {
+        Self {
+            registers: RegisterSet::new(),
+            float_registers: FloatRegisterSet::new(),
+            program: vec![],
+            ro_data: vec![],
+            heap: vec![],
+            stack: Vec::with_capacity(STACK_SIZE),
+            call_stack: vec![],
+            pc: 0,
+            remainder: 0,
+            equal_flag: false,
+            halted: false,
+            fault: None,
+            fuel: None,
+            opcode_costs: [DEFAULT_OPCODE_COST; 256],
+            breakpoints: Breakpoints::default(),
+            history: History::default(),
+            tracer: None,
+            profile: None,
+            host_functions: HostFunctions::default(),
+            logging: false,
+        }
+    }
+
+    pub fn read_pc(&self) -> &usize {
+        &self.pc
+    }
+
+    pub fn program_len(&self) -> usize {
+        self.program.len()
+    }
+
+    pub fn read_program(&self) -> &Vec<u8> {
+        &self.program
+    }
+
+    pub fn read_registers(&self) -> &[i32] {
+        &self.registers.registers
+    }
+
+    pub fn read_float_registers(&self) -> &[f64] {
+        &self.float_registers.registers
+    }
+
+    pub fn read_ro_data(&self) -> &[u8] {
+        &self.ro_data
+    }
+
+    /// Replaces the read-only segment that PRTS reads from.
+    pub fn set_ro_data(&mut self, ro_data: Vec<u8>) {
+        self.ro_data = ro_data;
+    }
+
+    /// Reads the null-terminated string starting at `offset` in the read-only segment.
+    pub fn read_ro_string(&self, offset: usize) -> Result<&str, VmErrorKind> {
+        host::read_ro_string(&self.ro_data, offset)
+    }
+
+    /// Makes `step` return a description of each executed instruction. Off by default,
+    /// since formatting it costs more than executing most instructions.
+    pub fn set_logging(&mut self, enabled: bool) {
+        self.logging = enabled;
+    }
+
+    /// Remaining fuel, or `None` if execution is unmetered
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Enables metering with the given amount of fuel, or disables it with `None`.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Adds fuel so an exhausted program can be resumed. Enables metering if it was off.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    pub fn opcode_cost(&self, opcode: OpCode) -> u64 {
+        self.opcode_costs[u8::from(opcode) as usize]
+    }
+
+    pub fn set_opcode_cost(&mut self, opcode: OpCode, cost: u64) {
+        self.opcode_costs[u8::from(opcode) as usize] = cost;
+    }
+
+    /// Whether the most recent step executed a HLT
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// The fault raised by the most recent step, if it failed
+    pub fn read_fault(&self) -> Option<&VmError> {
+        self.fault.as_ref()
+    }
+
+    pub fn read_heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    pub fn read_stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    pub fn read_call_stack(&self) -> &[usize] {
+        &self.call_stack
+    }
+
+    pub fn set_register(&mut self, index: usize, value: i32) -> Result<(), VmErrorKind> {
+        self.registers.set(index, value)
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// Overwrites heap bytes starting at `address`, without growing the heap.
+    pub fn write_heap(&mut self, address: usize, bytes: &[u8]) -> Result<(), VmErrorKind> {
+        match self.heap.get_mut(address..address + bytes.len()) {
+            Some(dest) => {
+                dest.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(VmErrorKind::HeapOutOfBounds {
+                address: address as i32,
+                width: bytes.len(),
+                len: self.heap.len(),
+            }),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pc = 0;
+        self.registers = RegisterSet::new();
+        self.float_registers = FloatRegisterSet::new();
+        self.remainder = 0;
+        self.program = vec![];
+        self.ro_data = vec![];
+        self.heap = vec![];
+        self.stack.clear();
+        self.call_stack.clear();
+        self.equal_flag = false;
+        self.halted = false;
+        self.fault = None;
+        self.breakpoints.resume_pc = None;
+        self.clear_history();
+    }
+
+    pub fn add_program(&mut self, command: &mut Vec<u8>) {
+        self.program.append(command);
+    }
+
+    /// Validates an executable image and loads it into a freshly reset VM,
+    /// positioning the program counter at its entry point.
+    pub fn load_executable(&mut self, bytes: &[u8]) -> Result<(), ExecutableError> {
+        let executable = Executable::from_bytes(bytes)?;
+        self.reset();
+        self.program = executable.code;
+        self.ro_data = executable.ro;
+        self.pc = executable.entry as usize;
+        Ok(())
+    }
+
+    /// Loads raw bytecode, without a header or read-only data, into a freshly reset VM.
+    pub fn load_bytecode(&mut self, bytecode: Vec<u8>) {
+        self.reset();
+        self.program = bytecode;
+    }
+
+    /// Inserting shifts the instructions after `address`, so the undo history is dropped.
+    pub fn insert_into_program(&mut self, command: &mut Vec<u8>, address: usize) {
+        self.clear_history();
+        let (left, right) = self.program.split_at(address);
+        let mut joined = left.to_vec();
+        joined.append(command);
+        joined.extend(right.to_vec());
+        self.program = joined;
+    }
+
+    /// Runs until the program halts, ends, faults or hits a breakpoint or watchpoint.
+    pub fn run(&mut self) -> RunOutcome {
+        loop {
+            if let Some(outcome) = self.run_once_outcome() {
+                return outcome;
+            }
+        }
+    }
+
+    /// Like `run`, but gives up after executing `max_instructions` instructions.
+    pub fn run_limited(&mut self, max_instructions: u64) -> RunOutcome {
+        for _ in 0..max_instructions {
+            if let Some(outcome) = self.run_once_outcome() {
+                return outcome;
+            }
+        }
+        RunOutcome::BudgetExhausted
+    }
+
+    /// Executes one instruction, returning an outcome if execution can't continue.
+    fn run_once_outcome(&mut self) -> Option<RunOutcome> {
+        if let Some(reason) = self.check_pc_breakpoints() {
+            return Some(RunOutcome::Stopped(reason));
+        }
+        let pc = self.pc;
+        let watched = self.snapshot_watchpoints();
+        match self.step() {
+            Ok((false, _)) => self
+                .check_watchpoints(pc, watched)
+                .map(RunOutcome::Stopped),
+            Ok((true, _)) if self.halted => Some(RunOutcome::Halted),
+            Ok((true, _)) => Some(RunOutcome::EndOfProgram),
+            Err(e) if e.kind == VmErrorKind::OutOfFuel => Some(RunOutcome::BudgetExhausted),
+            Err(e) => Some(RunOutcome::Faulted(e)),
+        }
+    }
+
+    /// Executes a single instruction, returning whether execution is done and, if logging
+    /// is on, a description of the instruction. On a fault the program counter is rolled
+    /// back to the faulting instruction and the error is kept for inspection via `read_fault`.
+    pub fn step(&mut self) -> Result<(bool, String), VmError> {
+        self.halted = false;
+        self.fault = None;
+        self.breakpoints.resume_pc = None;
+        let pc = self.pc;
+        let fuel = self.fuel;
+        let undo = self.begin_undo();
+        let trace = self.begin_trace();
+        let result = self.step_inner();
+        match result {
+            Ok(_) => {
+                if let Some(undo) = undo {
+                    self.record_undo(undo);
+                }
+                if pc < self.program.len() {
+                    self.record_profile(pc);
+                }
+            }
+            Err(ref e) => {
+                self.pc = pc;
+                self.fuel = fuel;
+                self.fault = Some(e.clone());
+            }
+        }
+        if let Some(trace) = trace {
+            self.record_trace(trace, result.as_ref().err());
+        }
+        result
+    }
+
+    fn step_inner(&mut self) -> Result<(bool, String), VmError> {
+        if self.pc >= self.program.len() {
+            return Ok((true, self.log("EOF")));
+        }
+        let pc = self.pc;
+        let instruction = self
+            .get_next_instruction()
+            .map_err(|kind| VmError::new(pc, None, kind))?;
+        let opcode = *instruction.opcode();
+        // Fuel is only spent once the instruction is known to be affordable,
+        // so an exhausted program resumes from the same instruction
+        if let Some(fuel) = self.fuel {
+            let cost = self.opcode_cost(opcode);
+            if fuel < cost {
+                return Err(VmError::new(pc, Some(opcode), VmErrorKind::OutOfFuel));
+            }
+            self.fuel = Some(fuel - cost);
+        }
+        if opcode == IGL {
+            return Err(VmError::new(
+                pc,
+                Some(opcode),
+                VmErrorKind::IllegalOpcode {
+                    byte: self.program[pc],
+                },
+            ));
+        }
+        let done = self
+            .execute(instruction)
+            .map_err(|kind| VmError::new(pc, Some(opcode), kind))?;
+        if !self.logging {
+            return Ok((done, String::new()));
+        }
+        let operands = instruction.operands();
+        let log_message = format!(
+            "{} {:04} {:04} {:04}{}",
+            opcode.padded(),
+            operands[0],
+            operands[1],
+            operands[2],
+            if opcode == HLT {
+                " ; HLT Encountered"
+            } else {
+                ""
+            }
+        );
+        Ok((done, log_message))
+    }
+
+    fn log(&self, message: &str) -> String {
+        if self.logging {
+            message.to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    /// Executes a decoded instruction, returning whether execution is done.
+    fn execute(&mut self, instruction: Instruction) -> Result<bool, VmErrorKind> {
+        let operands = instruction.operands();
+        let opcode = instruction.opcode();
+
+        match opcode {
+            LOAD => {
+                self.registers.set(
+                    operands[0] as usize,
+                    Self::conv_u8s_u16(&[operands[1], operands[2]]) as i32,
+                )?;
+            }
+            INC => {
+                self.registers.inc(operands[0] as usize)?;
+            }
+            DEC => {
+                self.registers.dec(operands[0] as usize)?;
+            }
+            JMP => {
+                self.pc = Self::jump_target(*self.registers.get(operands[0] as usize)? as i64)?;
+            }
+            JMPF => {
+                let offset = *self.registers.get(operands[0] as usize)? as i64;
+                self.pc = Self::jump_target(self.pc as i64 + offset)?;
+            }
+            JMPB => {
+                let offset = *self.registers.get(operands[0] as usize)? as i64;
+                self.pc = Self::jump_target(self.pc as i64 - offset)?;
+            }
+            ADD => {
+                self.registers.set(
+                    operands[2] as usize,
+                    self.registers
+                        .get(operands[0] as usize)?
+                        .wrapping_add(*self.registers.get(operands[1] as usize)?),
+                )?;
+            }
+            SUB => {
+                self.registers.set(
+                    operands[2] as usize,
+                    self.registers
+                        .get(operands[0] as usize)?
+                        .wrapping_sub(*self.registers.get(operands[1] as usize)?),
+                )?;
+            }
+            MUL => {
+                self.registers.set(
+                    operands[2] as usize,
+                    self.registers
+                        .get(operands[0] as usize)?
+                        .wrapping_mul(*self.registers.get(operands[1] as usize)?),
+                )?;
+            }
+            DIV => {
+                let register1 = *self.registers.get(operands[0] as usize)?;
+                let register2 = *self.registers.get(operands[1] as usize)?;
+                if register2 == 0 {
+                    return Err(VmErrorKind::DivideByZero);
+                }
+                self.registers
+                    .set(operands[2] as usize, register1.wrapping_div(register2))?;
+                self.remainder = register1.wrapping_rem(register2) as u32;
+            }
+            EQ => {
+                self.equal_flag = *self.registers.get(operands[0] as usize)?
+                    == *self.registers.get(operands[1] as usize)?;
+            }
+            NEQ => {
+                self.equal_flag = *self.registers.get(operands[0] as usize)?
+                    != *self.registers.get(operands[1] as usize)?;
+            }
+            GT => {
+                self.equal_flag = *self.registers.get(operands[0] as usize)?
+                    > *self.registers.get(operands[1] as usize)?;
+            }
+            LT => {
+                self.equal_flag = *self.registers.get(operands[0] as usize)?
+                    < *self.registers.get(operands[1] as usize)?;
+            }
+            GTE => {
+                self.equal_flag = *self.registers.get(operands[0] as usize)?
+                    >= *self.registers.get(operands[1] as usize)?;
+            }
+            LTE => {
+                self.equal_flag = *self.registers.get(operands[0] as usize)?
+                    <= *self.registers.get(operands[1] as usize)?;
+            }
+            JEQ => {
+                let target = *self.registers.get(operands[0] as usize)? as i64;
+                if self.equal_flag {
+                    self.pc = Self::jump_target(target)?;
+                }
+            }
+            JNE => {
+                let target = *self.registers.get(operands[0] as usize)? as i64;
+                if !self.equal_flag {
+                    self.pc = Self::jump_target(target)?;
+                }
+            }
+            ALOC => {
+                let bytes = *self.registers.get(operands[0] as usize)?;
+                let new_end = self.heap.len() as i64 + bytes as i64;
+                if new_end < 0 {
+                    return Err(VmErrorKind::InvalidAllocation { bytes });
+                }
+                self.heap.resize(new_end as usize, 0);
+            }
+            LOADM => {
+                let address = *self.registers.get(operands[1] as usize)?;
+                let range = self.heap_range(address, operands[2])?;
+                let value = self.heap[range]
+                    .iter()
+                    .fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+                self.registers.set(operands[0] as usize, value as i32)?;
+            }
+            SETM => {
+                let address = *self.registers.get(operands[0] as usize)?;
+                let value = *self.registers.get(operands[1] as usize)?;
+                let range = self.heap_range(address, operands[2])?;
+                let width = range.len();
+                self.heap[range].copy_from_slice(&value.to_be_bytes()[4 - width..]);
+            }
+            PRTS => {
+                let offset = Self::conv_u8s_u16(&[operands[0], operands[1]]) as i32;
+                self.call_host(host::SYS_PRINT_STRING, [offset, 0, 0, 0])?;
+            }
+            SYSCALL => {
+                self.syscall(Self::conv_u8s_u16(&[operands[0], operands[1]]))?;
+            }
+            SHL => {
+                let value = *self.registers.get(operands[0] as usize)? as u32;
+                let amount = self.shift_amount(operands[1])?;
+                self.registers.set(
+                    operands[2] as usize,
+                    value.checked_shl(amount).unwrap_or(0) as i32,
+                )?;
+            }
+            SHR => {
+                let value = *self.registers.get(operands[0] as usize)? as u32;
+                let amount = self.shift_amount(operands[1])?;
+                self.registers.set(
+                    operands[2] as usize,
+                    value.checked_shr(amount).unwrap_or(0) as i32,
+                )?;
+            }
+            AND => {
+                self.registers.set(
+                    operands[2] as usize,
+                    self.registers.get(operands[0] as usize)?
+                        & self.registers.get(operands[1] as usize)?,
+                )?;
+            }
+            OR => {
+                self.registers.set(
+                    operands[2] as usize,
+                    self.registers.get(operands[0] as usize)?
+                        | self.registers.get(operands[1] as usize)?,
+                )?;
+            }
+            XOR => {
+                self.registers.set(
+                    operands[2] as usize,
+                    self.registers.get(operands[0] as usize)?
+                        ^ self.registers.get(operands[1] as usize)?,
+                )?;
+            }
+            NOT => {
+                self.registers
+                    .set(operands[1] as usize, !*self.registers.get(operands[0] as usize)?)?;
+            }
+            PUSH => {
+                if self.stack.len() >= STACK_SIZE {
+                    return Err(VmErrorKind::StackOverflow);
+                }
+                let value = *self.registers.get(operands[0] as usize)?;
+                self.stack.push(value);
+            }
+            POP => {
+                let value = match self.stack.last() {
+                    Some(value) => *value,
+                    None => return Err(VmErrorKind::StackUnderflow),
+                };
+                self.registers.set(operands[0] as usize, value)?;
+                self.stack.pop();
+            }
+            CALL => {
+                if self.call_stack.len() >= CALL_STACK_SIZE {
+                    return Err(VmErrorKind::CallStackOverflow);
+                }
+                self.call_stack.push(self.pc);
+                self.pc = Self::conv_u8s_u16(&[operands[0], operands[1]]) as usize;
+            }
+            RET => {
+                self.pc = match self.call_stack.pop() {
+                    Some(address) => address,
+                    None => return Err(VmErrorKind::CallStackUnderflow),
+                };
+            }
+            LOADF64 => {
+                let value = self.next_f64()?;
+                self.float_registers.set(operands[0] as usize, value)?;
+            }
+            ADDF64 => {
+                self.float_registers.set(
+                    operands[2] as usize,
+                    self.float_registers.get(operands[0] as usize)?
+                        + self.float_registers.get(operands[1] as usize)?,
+                )?;
+            }
+            SUBF64 => {
+                self.float_registers.set(
+                    operands[2] as usize,
+                    self.float_registers.get(operands[0] as usize)?
+                        - self.float_registers.get(operands[1] as usize)?,
+                )?;
+            }
+            MULF64 => {
+                self.float_registers.set(
+                    operands[2] as usize,
+                    self.float_registers.get(operands[0] as usize)?
+                        * self.float_registers.get(operands[1] as usize)?,
+                )?;
+            }
+            DIVF64 => {
+                self.float_registers.set(
+                    operands[2] as usize,
+                    self.float_registers.get(operands[0] as usize)?
+                        / self.float_registers.get(operands[1] as usize)?,
+                )?;
+            }
+            EQF64 => {
+                self.equal_flag = *self.float_registers.get(operands[0] as usize)?
+                    == *self.float_registers.get(operands[1] as usize)?;
+            }
+            NEQF64 => {
+                self.equal_flag = *self.float_registers.get(operands[0] as usize)?
+                    != *self.float_registers.get(operands[1] as usize)?;
+            }
+            GTF64 => {
+                self.equal_flag = *self.float_registers.get(operands[0] as usize)?
+                    > *self.float_registers.get(operands[1] as usize)?;
+            }
+            GTEF64 => {
+                self.equal_flag = *self.float_registers.get(operands[0] as usize)?
+                    >= *self.float_registers.get(operands[1] as usize)?;
+            }
+            LTF64 => {
+                self.equal_flag = *self.float_registers.get(operands[0] as usize)?
+                    < *self.float_registers.get(operands[1] as usize)?;
+            }
+            LTEF64 => {
+                self.equal_flag = *self.float_registers.get(operands[0] as usize)?
+                    <= *self.float_registers.get(operands[1] as usize)?;
+            }
+            HLT => {
+                self.pc = self.program.len();
+                self.halted = true;
+                return Ok(true); // Done
+            }
+            NOP => {}
+            // Reserved opcodes without an implementation
+            _ => {
+                return Err(VmErrorKind::IllegalOpcode {
+                    byte: (*opcode).into(),
+                });
+            }
+        }
+        Ok(false)
+    }
+
+    fn get_next_instruction(&mut self) -> Result<Instruction, VmErrorKind> {
+        let word = match self.program.get(self.pc..self.pc + 4) {
+            Some(word) => word,
+            None => return Err(VmErrorKind::TruncatedInstruction),
+        };
+        // The slice is exactly 4 bytes long, so the conversion can't fail
+        let instruction = Instruction::decode(word.try_into().unwrap());
+        self.pc += 4;
+        Ok(instruction)
+    }
+
+    /// Validates a LOADM/SETM access of `width` bytes (1, 2 or 4; 0 means a word) at `address`.
+    /// Values are stored big-endian and narrower loads are zero-extended.
+    fn heap_range(&self, address: i32, width: u8) -> Result<Range<usize>, VmErrorKind> {
+        let width = match width {
+            0 => DEFAULT_MEMORY_WIDTH,
+            1 | 2 | 4 => width,
+            _ => return Err(VmErrorKind::InvalidMemoryWidth { width }),
+        } as usize;
+        if address < 0 || address as usize + width > self.heap.len() {
+            return Err(VmErrorKind::HeapOutOfBounds {
+                address,
+                width,
+                len: self.heap.len(),
+            });
+        }
+        Ok(address as usize..address as usize + width)
+    }
+
+    /// The heap range the instruction at `pc` will write, if it's a SETM with a valid access.
+    fn pending_heap_write(&self) -> Option<Range<usize>> {
+        let word = self.program.get(self.pc..self.pc + 4)?;
+        if OpCode::from(word[0]) != SETM {
+            return None;
+        }
+        let address = *self.registers.get(word[1] as usize).ok()?;
+        self.heap_range(address, word[3]).ok()
+    }
+
+    fn jump_target(target: i64) -> Result<usize, VmErrorKind> {
+        if target < 0 {
+            return Err(VmErrorKind::InvalidJump { target });
+        }
+        Ok(target as usize)
+    }
+
+    /// Decodes the shift-amount operand of SHL/SHR. The amount is either an immediate
+    /// (flagged with `SHIFT_IMMEDIATE`) or a register holding the count, read as unsigned.
+    /// Shifts are logical, and any count of 32 or more shifts every bit out.
+    fn shift_amount(&self, operand: u8) -> Result<u32, VmErrorKind> {
+        if operand & SHIFT_IMMEDIATE != 0 {
+            Ok((operand & !SHIFT_IMMEDIATE) as u32)
+        } else {
+            Ok(*self.registers.get(operand as usize)? as u32)
+        }
+    }
+
+    /// Reads the 8-byte big-endian f64 immediate that trails a LOADF64 instruction.
+    fn next_f64(&mut self) -> Result<f64, VmErrorKind> {
+        let bytes = match self.program.get(self.pc..self.pc + 8) {
+            Some(bytes) => bytes,
+            None => return Err(VmErrorKind::TruncatedInstruction),
+        };
+        let mut value = [0u8; 8];
+        value.copy_from_slice(bytes);
+        self.pc += 8;
+        Ok(f64::from_be_bytes(value))
+    }
+
+    fn conv_u8s_u16(bytes: &[u8]) -> u16 {
+        ((bytes[0] as u16) << 8) | (bytes[1] as u16)
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for VM {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut registers = String::from("[ ");
+        for (i, reg) in self.read_registers().iter().enumerate() {
+            registers.push_str(&format!("{}", reg));
+            if i < 31 {
+                registers.push_str(", ");
+            }
+        }
+        registers.push_str(" ]");
+        writeln!(f, "Registers: {}", registers)?;
+        let mut float_registers = String::from("[ ");
+        for (i, reg) in self.read_float_registers().iter().enumerate() {
+            float_registers.push_str(&format!("{}", reg));
+            if i < 31 {
+                float_registers.push_str(", ");
+            }
+        }
+        float_registers.push_str(" ]");
+        writeln!(f, "Float registers: {}", float_registers)?;
+        writeln!(f, "Heap (len: {}):", self.heap.len())?;
+        // Rows that are still entirely zero are skipped so written bytes stand out
+        for (row, bytes) in self.read_heap().chunks(16).enumerate() {
+            if bytes.iter().all(|b| *b == 0) {
+                continue;
+            }
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            writeln!(f, "  {:08X}: {}", row * 16, hex.join(" "))?;
+        }
+        writeln!(f, "Read-only data (len: {}): {:?}", self.ro_data.len(), self.read_ro_data())?;
+        writeln!(f, "Stack (len: {}): {:?}", self.stack.len(), self.read_stack())?;
+        writeln!(f, "Call stack: {:?}", self.read_call_stack())?;
+        writeln!(f, "PC: {}", self.pc)?;
+        if let Some(fault) = self.read_fault() {
+            writeln!(f, "Fault: {}", fault)?;
+        }
+        writeln!(f, "Remainder: {}", self.remainder)?;
+        if let Some(fuel) = self.fuel() {
+            writeln!(f, "Fuel: {}", fuel)?;
+        }
+        writeln!(f, "Equal flag: {}", self.equal_flag)?;
+        writeln!(f, "Program: {:?}", self.read_program())?;
+        Ok(())
+    }
+}
+
+/// Tests for vm
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(dead_code)]
+    trait VMTestHelpers {
+        fn with_program(self, program: Vec<u8>) -> Self;
+        fn run_for(&mut self, n: usize) -> Result<(), Box<dyn std::error::Error>>;
+        fn next_8_bits(&mut self) -> u8;
+        fn next_16_bits(&mut self) -> u16;
+        fn run_once(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+    }
+
+    impl VMTestHelpers for VM {
+        fn with_program(mut self, program: Vec<u8>) -> Self {
+            self.program = program;
+            self
+        }
+
+        fn run_for(&mut self, n: usize) -> Result<(), Box<dyn std::error::Error>> {
+            for _ in 0..n {
+                self.run_once()?;
+            }
+            Ok(())
+        }
+
+        fn next_8_bits(&mut self) -> u8 {
+            let byte = self.program[self.pc];
+            self.pc += 1;
+            byte
+        }
+
+        fn next_16_bits(&mut self) -> u16 {
+            // Shift value of first byte left by 8 bits, so last 8 bits are 00000000
+            // Logical or the second byte (as a u16 with first 8 bytes 00000000), creating a u16 from both bytes.
+            let result = ((self.program[self.pc] as u16) << 8) | (self.program[self.pc + 1] as u16);
+            self.pc += 2;
+            result
+        }
+
+        fn run_once(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+            self.step()?;
+            Ok(())
+        }
+    }
+
+    fn expand(num: u16) -> [u8; 2] {
+        [(num >> 8) as u8, num as u8]
+    }
+
+    fn create_load_unchecked(vm: &mut VM, register: u8, number: [u8; 2]) {
+        vm.program.extend(vec![LOAD as u8, register]);
+        vm.program.extend(number);
+    }
+
+    fn create_loadf64_unchecked(vm: &mut VM, register: u8, number: f64) {
+        vm.program.extend(vec![LOADF64 as u8, register, 0, 0]);
+        vm.program.extend(number.to_be_bytes());
+    }
+
+    #[test]
+    fn test_create_vm() -> Result<(), Box<dyn std::error::Error>> {
+        let test_vm = VM::new();
+        assert_eq!(test_vm.registers[0], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hlt() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new().with_program(vec![HLT as u8, 0, 0, 0]);
+        assert_eq!(test_vm.run(), RunOutcome::Halted);
+        assert_eq!(test_vm.pc, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_logging() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new().with_program(vec![INC as u8, 1, 0, 0, HLT as u8, 0, 0, 0]);
+        assert_eq!(test_vm.step()?, (false, String::new()));
+        test_vm.set_logging(true);
+        assert_eq!(
+            test_vm.step()?,
+            (true, "HLT  0000 0000 0000 ; HLT Encountered".to_string())
+        );
+        assert_eq!(test_vm.step()?, (true, "EOF".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_igl() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new().with_program(vec![IGL as u8, 0, 0, 0]);
+        let err = VmError::new(0, Some(IGL), VmErrorKind::IllegalOpcode { byte: IGL as u8 });
+        assert_eq!(test_vm.run(), RunOutcome::Faulted(err.clone()));
+        // The program counter is left pointing at the faulting instruction
+        assert_eq!(test_vm.pc, 0);
+        assert_eq!(test_vm.read_fault(), Some(&err));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_stops_run() {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(3));
+        test_vm.program.extend(vec![DIV as u8, 0, 1, 2]); // $1 is zero
+        create_load_unchecked(&mut test_vm, 3, expand(9));
+        let outcome = test_vm.run();
+        assert_eq!(
+            outcome,
+            RunOutcome::Faulted(VmError::new(4, Some(DIV), VmErrorKind::DivideByZero))
+        );
+        // Nothing after the fault ran, and the state before it is intact
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.registers[0], 3);
+        assert_eq!(test_vm.registers[3], 0);
+        assert!(format!("{}", test_vm).contains("Fault: Division by zero at pc 0004 (DIV)"));
+
+        // Fixing the state and resuming carries on from the faulting instruction
+        test_vm.registers[1] = 1;
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.registers[2], 3);
+        assert_eq!(test_vm.registers[3], 9);
+        assert_eq!(test_vm.read_fault(), None);
+    }
+
+    #[test]
+    fn test_truncated_trailing_instruction() {
+        let mut test_vm = VM::new().with_program(vec![INC as u8, 0, 0, 0, INC as u8, 0]);
+        assert_eq!(
+            test_vm.run(),
+            RunOutcome::Faulted(VmError::new(4, None, VmErrorKind::TruncatedInstruction))
+        );
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_faulting_pop_keeps_stack() {
+        let mut test_vm = VM::new().with_program(vec![POP as u8, 99, 0, 0]);
+        test_vm.stack.push(5);
+        assert!(test_vm.step().is_err());
+        assert_eq!(test_vm.read_stack(), &[5]);
+    }
+
+    #[test]
+    fn test_invalid_jump() {
+        let mut test_vm = VM::new().with_program(vec![JMPB as u8, 0, 0, 0]);
+        test_vm.registers[0] = 8;
+        assert_eq!(
+            test_vm.step().unwrap_err().kind,
+            VmErrorKind::InvalidJump { target: -4 }
+        );
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_arithmetic_wraps() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        test_vm.registers[3] = i32::MIN;
+        test_vm.registers[4] = -1;
+        test_vm.program.extend(vec![ADD as u8, 0, 1, 2]);
+        test_vm.program.extend(vec![DIV as u8, 3, 4, 5]);
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert_eq!(test_vm.registers[5], i32::MIN);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_limited() {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 1, [0, 0]);
+        test_vm.program.extend(vec![INC as u8, 0, 0, 0]);
+        test_vm.program.extend(vec![JMP as u8, 1, 0, 0]); // Infinite loop
+        assert_eq!(test_vm.run_limited(7), RunOutcome::BudgetExhausted);
+        assert_eq!(test_vm.registers[0], 2);
+        assert_eq!(test_vm.pc, 4);
+
+        let mut test_vm = VM::new().with_program(vec![HLT as u8, 0, 0, 0]);
+        assert_eq!(test_vm.run_limited(7), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn test_fuel_exhaustion_and_resume() {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 1, expand(4));
+        test_vm.program.extend(vec![INC as u8, 0, 0, 0]);
+        test_vm.program.extend(vec![JMP as u8, 1, 0, 0]); // Infinite loop
+        test_vm.set_fuel(Some(5));
+        assert_eq!(test_vm.run(), RunOutcome::BudgetExhausted);
+        assert_eq!(test_vm.fuel(), Some(0));
+        assert_eq!(test_vm.registers[0], 2);
+        assert_eq!(test_vm.pc, 4);
+
+        // Topping up resumes where execution stopped
+        test_vm.add_fuel(2);
+        assert_eq!(test_vm.run(), RunOutcome::BudgetExhausted);
+        assert_eq!(test_vm.registers[0], 3);
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_fuel_opcode_costs() {
+        let mut test_vm = VM::new();
+        test_vm.program.extend(vec![INC as u8, 0, 0, 0]);
+        test_vm.program.extend(vec![MUL as u8, 0, 0, 0]);
+        test_vm.program.extend(vec![HLT as u8, 0, 0, 0]);
+        test_vm.set_opcode_cost(MUL, 10);
+        test_vm.set_opcode_cost(HLT, 0);
+        assert_eq!(test_vm.opcode_cost(INC), DEFAULT_OPCODE_COST);
+        test_vm.set_fuel(Some(10));
+        assert_eq!(test_vm.run(), RunOutcome::BudgetExhausted);
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.fuel(), Some(9));
+
+        test_vm.add_fuel(1);
+        assert_eq!(test_vm.run(), RunOutcome::Halted);
+        assert_eq!(test_vm.fuel(), Some(0));
+    }
+
+    #[test]
+    fn test_fault_refunds_fuel() {
+        let mut test_vm = VM::new();
+        test_vm.program.extend(vec![DIV as u8, 0, 1, 2]); // Divides by zero
+        test_vm.set_fuel(Some(5));
+        for _ in 0..3 {
+            assert_eq!(
+                test_vm.run(),
+                RunOutcome::Faulted(VmError::new(0, Some(DIV), VmErrorKind::DivideByZero))
+            );
+            assert_eq!(test_vm.fuel(), Some(5));
+            assert_eq!(test_vm.pc, 0);
+        }
+    }
+
+    #[test]
+    fn test_unmetered_by_default() {
+        let mut test_vm = VM::new().with_program(vec![INC as u8, 0, 0, 0]);
+        assert_eq!(test_vm.fuel(), None);
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.fuel(), None);
+    }
+
+    #[test]
+    fn test_illegal_opcodes() {
+        // Bytes that aren't opcodes at all
+        let mut test_vm = VM::new().with_program(vec![0xFF, 0, 0, 0]);
+        let err = test_vm.step().unwrap_err();
+        assert_eq!(err.opcode, Some(IGL));
+        assert_eq!(err.kind, VmErrorKind::IllegalOpcode { byte: 0xFF });
+
+        // Reserved opcodes without an implementation
+        let mut test_vm = VM::new().with_program(vec![NOP as u8, 0, 0, 0, LUI as u8, 0, 0, 0]);
+        test_vm.run_once().unwrap();
+        let err = test_vm.step().unwrap_err();
+        assert_eq!(err.pc, 4);
+        assert_eq!(err.opcode, Some(LUI));
+        assert_eq!(err.kind, VmErrorKind::IllegalOpcode { byte: LUI as u8 });
+    }
+
+    #[test]
+    fn test_register_out_of_bounds() {
+        let mut test_vm = VM::new().with_program(vec![INC as u8, 32, 0, 0]);
+        assert_eq!(
+            test_vm.step(),
+            Err(VmError::new(
+                0,
+                Some(INC),
+                VmErrorKind::RegisterOutOfBounds { index: 32 }
+            ))
+        );
+        let mut test_vm = VM::new().with_program(vec![ADDF64 as u8, 0, 40, 1]);
+        assert_eq!(
+            test_vm.step().unwrap_err().kind,
+            VmErrorKind::FloatRegisterOutOfBounds { index: 40 }
+        );
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let mut test_vm = VM::new().with_program(vec![DIV as u8, 0, 1, 2]);
+        test_vm.registers[0] = 10;
+        test_vm.registers[2] = 7;
+        let err = test_vm.step().unwrap_err();
+        assert_eq!(err.kind, VmErrorKind::DivideByZero);
+        assert_eq!(test_vm.registers[2], 7);
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut test_vm = VM::new().with_program(vec![HLT as u8, 0]);
+        assert_eq!(
+            test_vm.step(),
+            Err(VmError::new(0, None, VmErrorKind::TruncatedInstruction))
+        );
+    }
+
+    #[test]
+    fn test_invalid_allocation() {
+        let mut test_vm = VM::new().with_program(vec![ALOC as u8, 0, 0, 0]);
+        test_vm.registers[0] = -1;
+        assert_eq!(
+            test_vm.step().unwrap_err().kind,
+            VmErrorKind::InvalidAllocation { bytes: -1 }
+        );
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = VmError::new(8, Some(DIV), VmErrorKind::DivideByZero);
+        assert_eq!(err.to_string(), "Division by zero at pc 0008 (DIV)");
+        let err = VmError::new(12, None, VmErrorKind::TruncatedInstruction);
+        assert_eq!(
+            err.to_string(),
+            "Instruction runs past the end of the program at pc 0012"
+        );
+    }
+
+    #[test]
+    fn test_load() -> Result<(), Box<dyn std::error::Error>> {
+        let mut program: Vec<u8> = vec![LOAD as u8, 0];
+        program.extend(expand(500));
+
+        let mut test_vm = VM::new().with_program(program);
+        test_vm.run_once()?;
+        assert_eq!(test_vm.registers[0], 500);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(10));
+        create_load_unchecked(&mut test_vm, 1, expand(15));
+
+        test_vm.program.extend(vec![ADD as u8, 0, 1, 2]);
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.registers[2], 25);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sub() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(10));
+        create_load_unchecked(&mut test_vm, 1, expand(15));
+
+        test_vm.program.extend(vec![SUB as u8, 1, 0, 2]);
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.registers[2], 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mul() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(2));
+        create_load_unchecked(&mut test_vm, 1, expand(15));
+
+        test_vm.program.extend(vec![MUL as u8, 0, 1, 2]);
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.registers[2], 30);
+        Ok(())
+    }
+
+    #[test]
+    fn test_div() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(30));
+        create_load_unchecked(&mut test_vm, 1, expand(15));
+
+        test_vm.program.extend(vec![DIV as u8, 0, 1, 2]);
+
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.registers[2], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_div_remainder() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(6));
+        create_load_unchecked(&mut test_vm, 1, expand(4));
+
+        test_vm.program.extend(vec![DIV as u8, 0, 1, 2]);
+
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.remainder, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_jmp() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 1, [0, 0]); // load register 1 with 0
+        test_vm.program.extend(vec![JMP as u8, 1, 0, 0]); // Jump to the location in register 1 (the beginning of the program, infinite loop)
+        test_vm.run_for(2)?; // Execute the load and jump, don't allow infinite loop
+        assert_eq!(test_vm.pc, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_jmpf() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(4)); // Load 8 into register 0
+        test_vm.program.extend(vec![JMPF as u8, 0, 0, 0]); // Jump 8 bytes forwards
+        create_load_unchecked(&mut test_vm, 0, expand(15)); // Load 15 into register 0 (should be skipped)
+
+        test_vm.run_for(3)?;
+        assert_eq!(test_vm.pc, 12);
+        assert_eq!(test_vm.registers[0], 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_jmpb() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.pc = 4; // skip first load
+        create_load_unchecked(&mut test_vm, 0, expand(0)); // Load 0 into register 0
+        create_load_unchecked(&mut test_vm, 0, expand(12)); // Load 12 into register 0
+        test_vm.program.extend(vec![JMPB as u8, 0, 0, 0]); // Jump 8 bytes back (to first load)
+
+        test_vm.run_for(3)?;
+        assert_eq!(test_vm.registers[0], 0); // Ensure first load executed after jump
+        Ok(())
+    }
+
+    #[test]
+    fn test_eq_true() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(10));
+        create_load_unchecked(&mut test_vm, 1, expand(10));
+        test_vm.program.extend(vec![EQ as u8, 0, 1, 0]);
+        test_vm.run_for(3)?;
+        assert!(test_vm.equal_flag);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eq_false() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(20));
+        create_load_unchecked(&mut test_vm, 1, expand(10));
+        test_vm.program.extend(vec![EQ as u8, 0, 1, 0]);
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert!(!test_vm.equal_flag);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gt() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(20));
+        create_load_unchecked(&mut test_vm, 1, expand(10));
+        test_vm.program.extend(vec![GT as u8, 0, 1, 0]);
+        test_vm.run_for(3)?;
+        assert!(test_vm.equal_flag);
+        test_vm.program.extend(vec![GT as u8, 1, 0, 0]);
+        test_vm.run_once()?;
+        assert!(!test_vm.equal_flag);
+        test_vm.program.extend(vec![GT as u8, 0, 0, 0]);
+        test_vm.run_once()?;
+        assert!(!test_vm.equal_flag);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lt() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(10));
+        create_load_unchecked(&mut test_vm, 1, expand(20));
+        test_vm.program.extend(vec![LT as u8, 0, 1, 0]);
+        test_vm.run_for(3)?;
+        assert!(test_vm.equal_flag);
+        test_vm.program.extend(vec![LT as u8, 1, 0, 0]);
+        test_vm.run_once()?;
+        assert!(!test_vm.equal_flag);
+        test_vm.program.extend(vec![LT as u8, 0, 0, 0]);
+        test_vm.run_once()?;
+        assert!(!test_vm.equal_flag);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gte() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(20));
+        create_load_unchecked(&mut test_vm, 1, expand(10));
+        test_vm.program.extend(vec![GTE as u8, 0, 1, 0]);
+        test_vm.run_for(3)?;
+        assert!(test_vm.equal_flag);
+        test_vm.program.extend(vec![GTE as u8, 0, 0, 0]);
+        test_vm.run_once()?;
+        assert!(test_vm.equal_flag);
+        test_vm.program.extend(vec![GTE as u8, 1, 0, 0]);
+        test_vm.run_once()?;
+        assert!(!test_vm.equal_flag);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lte() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(10));
+        create_load_unchecked(&mut test_vm, 1, expand(20));
+        test_vm.program.extend(vec![LTE as u8, 0, 1, 0]);
+        test_vm.run_for(3)?;
+        assert!(test_vm.equal_flag);
+        test_vm.program.extend(vec![LTE as u8, 0, 0, 0]);
+        test_vm.run_once()?;
+        assert!(test_vm.equal_flag);
+        test_vm.program.extend(vec![LTE as u8, 1, 0, 0]);
+        test_vm.run_once()?;
+        assert!(!test_vm.equal_flag);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lt_bounded_loop() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_load_unchecked(&mut test_vm, 0, expand(0)); // counter
+        create_load_unchecked(&mut test_vm, 1, expand(5)); // bound
+        create_load_unchecked(&mut test_vm, 2, expand(12)); // loop start
+        test_vm.program.extend(vec![INC as u8, 0, 0, 0]);
+        test_vm.program.extend(vec![LT as u8, 0, 1, 0]);
+        test_vm.program.extend(vec![JEQ as u8, 2, 0, 0]); // Loop while $0 < $1
+        test_vm.program.extend(vec![HLT as u8, 0, 0, 0]);
+        assert_eq!(test_vm.run(), RunOutcome::Halted);
+        assert_eq!(test_vm.registers[0], 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_executable() -> Result<(), Box<dyn std::error::Error>> {
+        let code = vec![INC as u8, 0, 0, 0, PRTS as u8, 0, 0, 0, HLT as u8, 0, 0, 0];
+        let exe = Executable::new(code.clone(), b"ok\0".to_vec(), 4);
+        let mut test_vm = VM::new();
+        test_vm.registers[3] = 9;
+        test_vm.load_executable(&exe.to_bytes())?;
+        assert_eq!(test_vm.program, code);
+        assert_eq!(test_vm.read_ro_string(0), Ok("ok"));
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.registers[3], 0);
+        assert_eq!(test_vm.run(), RunOutcome::Halted);
+        assert_eq!(test_vm.registers[0], 0); // The INC before the entry point is skipped
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_executable_rejects_bad_image() {
+        let mut test_vm = VM::new().with_program(vec![HLT as u8, 0, 0, 0]);
+        let mut bytes = Executable::new(vec![], vec![], 0).to_bytes();
+        bytes[5] = 99; // Version
+        assert_eq!(
+            test_vm.load_executable(&bytes),
+            Err(ExecutableError::UnsupportedVersion { version: 99 })
+        );
+        assert!(test_vm.load_executable(b"#!/bin/sh").is_err());
+        // A rejected image leaves the VM untouched
+        assert_eq!(test_vm.program, vec![HLT as u8, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_read_ro_string() {
+        let mut test_vm = VM::new();
+        test_vm.set_ro_data(b"Hello\0World\0\xFF".to_vec());
+        assert_eq!(test_vm.read_ro_string(0), Ok("Hello"));
+        assert_eq!(test_vm.read_ro_string(6), Ok("World"));
+        assert_eq!(test_vm.read_ro_string(8), Ok("rld"));
+        assert!(test_vm.read_ro_string(12).is_err()); // No terminator
+        assert!(test_vm.read_ro_string(13).is_err()); // Past the end
+    }
+
+    #[test]
+    fn test_prts() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.set_ro_data(b"Hi\0".to_vec());
+        test_vm.program.extend(vec![PRTS as u8, 0, 0, 0]);
+        test_vm.program.extend(vec![PRTS as u8, 0, 9, 0]);
+        test_vm.run_once()?;
+        assert!(test_vm.step().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_setm_loadm() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 16];
+        test_vm.registers[0] = 4; // address
+        test_vm.registers[1] = 0x1234_5678;
+        test_vm.program.extend(vec![SETM as u8, 0, 1, 0]); // Word store
+        test_vm.program.extend(vec![LOADM as u8, 2, 0, 0]); // Word load
+        test_vm.program.extend(vec![LOADM as u8, 3, 0, 2]); // Halfword load
+        test_vm.program.extend(vec![LOADM as u8, 4, 0, 1]); // Byte load
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(&test_vm.heap[4..8], &[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(test_vm.registers[2], 0x1234_5678);
+        assert_eq!(test_vm.registers[3], 0x1234);
+        assert_eq!(test_vm.registers[4], 0x12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_setm_narrow() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[0] = 1;
+        test_vm.registers[1] = -1;
+        test_vm.program.extend(vec![SETM as u8, 0, 1, 1]);
+        test_vm.program.extend(vec![SETM as u8, 0, 1, 2]);
+        test_vm.program.extend(vec![LOADM as u8, 2, 0, 2]);
+        test_vm.run_once()?;
+        assert_eq!(test_vm.heap, vec![0, 0xFF, 0, 0]);
+        test_vm.run_for(2)?;
+        assert_eq!(test_vm.heap, vec![0, 0xFF, 0xFF, 0]);
+        // Narrow loads are zero-extended
+        assert_eq!(test_vm.registers[2], 0xFFFF);
+        Ok(())
+    }
+
+    #[test]
+    fn test_heap_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[0] = 2;
+        test_vm.registers[1] = -1;
+        test_vm.program.extend(vec![LOADM as u8, 2, 0, 4]); // Runs past the end
+        test_vm.program.extend(vec![SETM as u8, 1, 0, 1]); // Negative address
+        test_vm.program.extend(vec![LOADM as u8, 2, 0, 3]); // Invalid width
+        assert!(test_vm.step().is_err());
+        assert!(test_vm.step().is_err());
+        assert!(test_vm.step().is_err());
+        assert_eq!(test_vm.heap, vec![0; 4]);
+    }
+
+    #[test]
+    fn test_heap_display() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 48];
+        test_vm.heap[33] = 0xAB;
+        let dump = format!("{}", test_vm);
+        assert!(dump.contains("Heap (len: 48):\n  00000020: 00 AB 00"));
+        assert!(!dump.contains("00000000:"));
+    }
+
+    #[test]
+    fn test_shl() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0b1011;
+        test_vm.registers[1] = 4;
+        test_vm.program.extend(vec![SHL as u8, 0, 1, 2]);
+        test_vm.program.extend(vec![SHL as u8, 0, SHIFT_IMMEDIATE | 3, 3]);
+        test_vm.program.extend(vec![SHL as u8, 0, SHIFT_IMMEDIATE | 32, 4]);
+        test_vm.program.extend(vec![SHL as u8, 0, SHIFT_IMMEDIATE | 31, 5]);
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.registers[2], 0b1011_0000);
+        assert_eq!(test_vm.registers[3], 0b101_1000);
+        assert_eq!(test_vm.registers[4], 0);
+        assert_eq!(test_vm.registers[5], i32::MIN);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shr_is_logical() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.registers[1] = 28;
+        test_vm.registers[6] = -1; // Negative counts read as huge unsigned amounts
+        test_vm.program.extend(vec![SHR as u8, 0, 1, 2]);
+        test_vm.program.extend(vec![SHR as u8, 0, SHIFT_IMMEDIATE | 1, 3]);
+        test_vm.program.extend(vec![SHR as u8, 0, SHIFT_IMMEDIATE | 40, 4]);
+        test_vm.program.extend(vec![SHR as u8, 0, 6, 5]);
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.registers[2], 0xF);
+        assert_eq!(test_vm.registers[3], i32::MAX);
+        assert_eq!(test_vm.registers[4], 0);
+        assert_eq!(test_vm.registers[5], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitwise() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        test_vm.program.extend(vec![AND as u8, 0, 1, 2]);
+        test_vm.program.extend(vec![OR as u8, 0, 1, 3]);
+        test_vm.program.extend(vec![XOR as u8, 0, 1, 4]);
+        test_vm.program.extend(vec![NOT as u8, 0, 5, 0]);
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.registers[2], 0b1000);
+        assert_eq!(test_vm.registers[3], 0b1110);
+        assert_eq!(test_vm.registers[4], 0b0110);
+        assert_eq!(test_vm.registers[5], !0b1100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_pop() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 42;
+        test_vm.registers[1] = -7;
+        test_vm.program.extend(vec![PUSH as u8, 0, 0, 0]);
+        test_vm.program.extend(vec![PUSH as u8, 1, 0, 0]);
+        test_vm.program.extend(vec![POP as u8, 2, 0, 0]);
+        test_vm.program.extend(vec![POP as u8, 3, 0, 0]);
+        test_vm.run_for(2)?;
+        assert_eq!(test_vm.read_stack(), &[42, -7]);
+        test_vm.run_for(2)?;
+        assert_eq!(test_vm.registers[2], -7);
+        assert_eq!(test_vm.registers[3], 42);
+        assert!(test_vm.stack.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stack_overflow_underflow() {
+        let mut test_vm = VM::new().with_program(vec![POP as u8, 0, 0, 0]);
+        assert_eq!(
+            test_vm.step(),
+            Err(VmError::new(0, Some(POP), VmErrorKind::StackUnderflow))
+        );
+
+        let mut test_vm = VM::new().with_program(vec![PUSH as u8, 0, 0, 0]);
+        test_vm.stack = vec![0; STACK_SIZE];
+        assert_eq!(test_vm.step().unwrap_err().kind, VmErrorKind::StackOverflow);
+        assert_eq!(test_vm.stack.len(), STACK_SIZE);
+    }
+
+    #[test]
+    fn test_call_ret() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.program.extend(vec![CALL as u8, 0, 12, 0]); // Call the subroutine at 12
+        test_vm.program.extend(vec![INC as u8, 1, 0, 0]);
+        test_vm.program.extend(vec![HLT as u8, 0, 0, 0]);
+        test_vm.program.extend(vec![INC as u8, 0, 0, 0]); // Subroutine
+        test_vm.program.extend(vec![RET as u8, 0, 0, 0]);
+        test_vm.run_once()?;
+        assert_eq!(test_vm.pc, 12);
+        assert_eq!(test_vm.read_call_stack(), &[4]);
+        assert_eq!(test_vm.run(), RunOutcome::Halted);
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(test_vm.registers[1], 1);
+        assert!(test_vm.call_stack.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_call_stack_overflow_underflow() {
+        let mut test_vm = VM::new().with_program(vec![RET as u8, 0, 0, 0]);
+        assert_eq!(test_vm.step().unwrap_err().kind, VmErrorKind::CallStackUnderflow);
+
+        // Unbounded recursion
+        let mut test_vm = VM::new().with_program(vec![CALL as u8, 0, 0, 0]);
+        let mut result = Ok((false, String::new()));
+        for _ in 0..=CALL_STACK_SIZE {
+            result = test_vm.step();
+        }
+        assert_eq!(result.unwrap_err().kind, VmErrorKind::CallStackOverflow);
+        assert_eq!(test_vm.call_stack.len(), CALL_STACK_SIZE);
+    }
+
+    #[test]
+    fn test_loadf64() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_loadf64_unchecked(&mut test_vm, 3, 2.75);
+        test_vm.run_once()?;
+        assert_eq!(test_vm.float_registers[3], 2.75);
+        assert_eq!(test_vm.pc, 12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_loadf64_truncated() {
+        let mut test_vm = VM::new().with_program(vec![LOADF64 as u8, 0, 0, 0, 1, 2]);
+        assert!(test_vm.step().is_err());
+    }
+
+    #[test]
+    fn test_f64_arithmetic() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        create_loadf64_unchecked(&mut test_vm, 0, 7.5);
+        create_loadf64_unchecked(&mut test_vm, 1, 2.5);
+        test_vm.program.extend(vec![ADDF64 as u8, 0, 1, 2]);
+        test_vm.program.extend(vec![SUBF64 as u8, 0, 1, 3]);
+        test_vm.program.extend(vec![MULF64 as u8, 0, 1, 4]);
+        test_vm.program.extend(vec![DIVF64 as u8, 0, 1, 5]);
+        assert_eq!(test_vm.run(), RunOutcome::EndOfProgram);
+        assert_eq!(test_vm.float_registers[2], 10.0);
+        assert_eq!(test_vm.float_registers[3], 5.0);
+        assert_eq!(test_vm.float_registers[4], 18.75);
+        assert_eq!(test_vm.float_registers[5], 3.0);
+        // The integer register file is untouched by float instructions
+        assert_eq!(test_vm.registers[2], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_f64_comparisons() -> Result<(), Box<dyn std::error::Error>> {
+        let cases = [
+            (EQF64, [false, true, false]),
+            (NEQF64, [true, false, true]),
+            (GTF64, [false, false, true]),
+            (GTEF64, [false, true, true]),
+            (LTF64, [true, false, false]),
+            (LTEF64, [true, true, false]),
+        ];
+        for (opcode, expected) in cases {
+            let mut test_vm = VM::new();
+            test_vm.float_registers[0] = 1.5;
+            test_vm.float_registers[1] = 2.5;
+            test_vm.float_registers[2] = 2.5;
+            test_vm.program.extend(vec![opcode as u8, 0, 1, 0]);
+            test_vm.program.extend(vec![opcode as u8, 1, 2, 0]);
+            test_vm.program.extend(vec![opcode as u8, 2, 0, 0]);
+            for expect in expected {
+                test_vm.run_once()?;
+                assert_eq!(test_vm.equal_flag, expect, "{}", opcode);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_jeq() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.equal_flag = true;
+        test_vm.program = vec![JEQ as u8, 0, 0, 0];
+        test_vm.run_once()?;
+        assert_eq!(test_vm.pc, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_jne() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 7;
+        test_vm.equal_flag = false;
+        test_vm.program = vec![JNE as u8, 0, 0, 0];
+        test_vm.run_once()?;
+        assert_eq!(test_vm.pc, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_aloc() -> Result<(), Box<dyn std::error::Error>> {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 1024;
+        test_vm.program = vec![ALOC as u8, 0, 0, 0];
+        test_vm.run_once()?;
+        Ok(())
+    }
+}
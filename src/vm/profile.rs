@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+
+use super::VM;
+use crate::assembler::{disassembler::disassemble_instruction, SymbolTable};
+use crate::opcode::{OpCode, OpCode::*};
+
+/// Execution counts gathered while profiling is on. Only instructions that
+/// completed are counted; a faulting instruction isn't.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    total: u64,
+    /// Indexed by opcode byte
+    opcodes: [u64; 256],
+    pcs: HashMap<usize, u64>,
+    branches: HashMap<usize, BranchCounts>,
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            total: 0,
+            opcodes: [0; 256],
+            pcs: HashMap::new(),
+            branches: HashMap::new(),
+        }
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.total.max(1) as f64
+    }
+
+    /// Renders the counts as tables sorted hottest first. Addresses are shown relative to
+    /// the nearest code label when a symbol table is given.
+    pub fn report(&self, program: &[u8], symbols: Option<&SymbolTable>) -> String {
+        let location = |pc: usize| match symbols.and_then(|s| s.label_for_offset(pc as u32)) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, distance)) => format!("{}+{}", label, distance),
+            None => String::new(),
+        };
+        let instruction = |pc: usize| {
+            program
+                .get(pc..)
+                .and_then(disassemble_instruction)
+                .map(|(text, _)| text)
+                .unwrap_or_default()
+        };
+
+        let mut out = format!("Profile: {} instructions executed\n", self.total);
+        let mut opcodes: Vec<(u8, u64)> = (0..=255u8)
+            .map(|opcode| (opcode, self.opcodes[opcode as usize]))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        out.push_str("\nBy opcode:\n");
+        out.push_str(&format!("{:>12} {:>7}  opcode\n", "count", "%"));
+        for (opcode, count) in opcodes {
+            out.push_str(&format!(
+                "{:>12} {:>6.2}%  {}\n",
+                count,
+                self.percent(count),
+                OpCode::from(opcode)
+            ));
+        }
+
+        let mut pcs: Vec<(usize, u64)> = self.pcs.iter().map(|(pc, c)| (*pc, *c)).collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        out.push_str("\nBy pc:\n");
+        out.push_str(&format!(
+            "{:>12} {:>7}  {:<4}  {:<16} instruction\n",
+            "count", "%", "pc", "location"
+        ));
+        for (pc, count) in pcs {
+            out.push_str(&format!(
+                "{:>12} {:>6.2}%  {:04}  {:<16} {}\n",
+                count,
+                self.percent(count),
+                pc,
+                location(pc),
+                instruction(pc)
+            ));
+        }
+
+        if !self.branches.is_empty() {
+            let mut branches: Vec<(usize, BranchCounts)> =
+                self.branches.iter().map(|(pc, c)| (*pc, *c)).collect();
+            branches.sort_by(|a, b| {
+                let total = |c: &BranchCounts| c.taken + c.not_taken;
+                total(&b.1).cmp(&total(&a.1)).then(a.0.cmp(&b.0))
+            });
+            out.push_str("\nBranches:\n");
+            out.push_str(&format!(
+                "{:>12} {:>12}  {:<4}  {:<16} instruction\n",
+                "taken", "not taken", "pc", "location"
+            ));
+            for (pc, counts) in branches {
+                out.push_str(&format!(
+                    "{:>12} {:>12}  {:04}  {:<16} {}\n",
+                    counts.taken,
+                    counts.not_taken,
+                    pc,
+                    location(pc),
+                    instruction(pc)
+                ));
+            }
+        }
+        out
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read accessors for individual counts
+impl Profile {
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn opcode_count(&self, opcode: OpCode) -> u64 {
+        self.opcodes[u8::from(opcode) as usize]
+    }
+
+    pub fn pc_count(&self, pc: usize) -> u64 {
+        self.pcs.get(&pc).copied().unwrap_or(0)
+    }
+
+    pub fn branch_counts(&self, pc: usize) -> BranchCounts {
+        self.branches.get(&pc).copied().unwrap_or_default()
+    }
+}
+
+impl VM {
+    /// Starts counting executed instructions, keeping any counts gathered so far.
+    pub fn enable_profiling(&mut self) {
+        if self.profile.is_none() {
+            self.profile = Some(Profile::new());
+        }
+    }
+
+    /// Stops profiling and hands back what was gathered.
+    pub fn disable_profiling(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Counts the instruction at `pc`, which has just executed.
+    pub(super) fn record_profile(&mut self, pc: usize) {
+        let profile = match self.profile.as_mut() {
+            Some(profile) => profile,
+            None => return,
+        };
+        let byte = self.program[pc];
+        profile.total += 1;
+        profile.opcodes[byte as usize] += 1;
+        *profile.pcs.entry(pc).or_insert(0) += 1;
+        // The jumps don't touch the flag, so it still says which way JEQ/JNE went
+        let taken = match OpCode::from(byte) {
+            JMP | JMPF | JMPB => true,
+            JEQ => self.equal_flag,
+            JNE => !self.equal_flag,
+            _ => return,
+        };
+        let counts = profile.branches.entry(pc).or_default();
+        if taken {
+            counts.taken += 1;
+        } else {
+            counts.not_taken += 1;
+        }
+    }
+}
+
+/// Tests for profile
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::RunOutcome;
+
+    fn profiled_loop() -> (VM, Assembler) {
+        let mut asm = Assembler::new();
+        let source = r".data
+.code
+    load $1 #3
+    load $2 @loop
+loop:
+    inc $0
+    lt $0 $1
+    jeq $2
+    hlt";
+        let bytecode = asm.assemble(source).unwrap().clone();
+        let mut vm = VM::new();
+        vm.program = bytecode;
+        vm.enable_profiling();
+        (vm, asm)
+    }
+
+    #[test]
+    fn test_profile_counts() {
+        let (mut vm, _) = profiled_loop();
+        assert_eq!(vm.run(), RunOutcome::Halted);
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.total(), 2 + 3 * 3 + 1);
+        assert_eq!(profile.opcode_count(INC), 3);
+        assert_eq!(profile.opcode_count(LOAD), 2);
+        assert_eq!(profile.pc_count(8), 3);
+        assert_eq!(profile.pc_count(20), 1);
+        assert_eq!(
+            profile.branch_counts(16),
+            BranchCounts {
+                taken: 2,
+                not_taken: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_profile_skips_faults_and_toggles() {
+        let mut vm = VM::new();
+        vm.program = vec![INC as u8, 0, 0, 0, DIV as u8, 0, 1, 2];
+        assert_eq!(vm.run_limited(1), RunOutcome::BudgetExhausted);
+        vm.enable_profiling();
+        assert!(matches!(vm.run(), RunOutcome::Faulted(_)));
+        assert_eq!(vm.profile().unwrap().total(), 0);
+
+        vm.reset();
+        vm.program = vec![INC as u8, 0, 0, 0];
+        vm.run();
+        assert_eq!(vm.disable_profiling().unwrap().total(), 1);
+        assert!(vm.profile().is_none());
+    }
+
+    #[test]
+    fn test_profile_report() {
+        let (mut vm, asm) = profiled_loop();
+        vm.run();
+        let report = vm
+            .profile()
+            .unwrap()
+            .report(&vm.program, Some(&asm.symbols));
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "Profile: 12 instructions executed");
+        // Hottest first, ties broken by opcode number / address
+        assert!(lines[4].ends_with("LT"), "{}", lines[4]);
+        assert!(lines[5].ends_with("JEQ"), "{}", lines[5]);
+        assert!(report.contains("0008  loop             inc $0"));
+        assert!(report.contains("0016  loop+8           jeq $2"));
+        assert!(report.contains("           2            1  0016"));
+
+        let report = vm.profile().unwrap().report(&vm.program, None);
+        assert!(report.contains("0008                   inc $0"));
+    }
+}
@@ -0,0 +1,325 @@
+use std::io::{Cursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{CALL_STACK_SIZE, DEFAULT_OPCODE_COST, STACK_SIZE, VM};
+
+/// Magic number identifying an Iridium VM snapshot
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"IRSN";
+/// Current version of the snapshot format
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// Layout, in order:
+/// ```text
+/// magic | version
+/// pc (u64) | 32 registers (i32) | 32 float registers (f64) | remainder (u32)
+/// equal flag (u8) | halted (u8) | metered (u8) | fuel (u64)
+/// opcode cost count (u16) | (opcode (u8) | cost (u64)) for each non-default cost
+/// program, read-only data, heap  (u32 length, then bytes)
+/// stack (u32 length, then i32s) | call stack (u32 length, then u32s)
+/// ```
+/// All integers are big-endian, like executables. Breakpoints, undo history, the
+/// last fault, logging, tracing and profiling are debugger state, and host functions
+/// belong to the embedder, so none of them are part of a snapshot.
+#[derive(Debug, PartialEq, Clone)]
+pub enum SnapshotError {
+    BadMagic { found: Vec<u8> },
+    UnsupportedVersion { version: u16 },
+    Truncated,
+    TrailingData { len: usize },
+    StackTooDeep { len: usize },
+    CallStackTooDeep { len: usize },
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SnapshotError::BadMagic { found } => {
+                write!(f, "Bad magic number {:02X?}, not an Iridium snapshot", found)
+            }
+            SnapshotError::UnsupportedVersion { version } => {
+                write!(
+                    f,
+                    "Unsupported snapshot version {} (supported: {})",
+                    version, SNAPSHOT_VERSION
+                )
+            }
+            SnapshotError::Truncated => write!(f, "Snapshot is truncated"),
+            SnapshotError::TrailingData { len } => {
+                write!(f, "Snapshot has {} unexpected trailing bytes", len)
+            }
+            SnapshotError::StackTooDeep { len } => {
+                write!(f, "Snapshot stack of {} values exceeds {}", len, STACK_SIZE)
+            }
+            SnapshotError::CallStackTooDeep { len } => {
+                write!(
+                    f,
+                    "Snapshot call stack of {} frames exceeds {}",
+                    len, CALL_STACK_SIZE
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(_: std::io::Error) -> Self {
+        SnapshotError::Truncated
+    }
+}
+
+/// Reads a u32 length and checks it against what's left, so a corrupt length
+/// can't trigger a huge allocation.
+fn read_len(reader: &mut Cursor<&[u8]>, item_size: usize) -> Result<usize, SnapshotError> {
+    let len = reader.read_u32::<BigEndian>()? as usize;
+    let remaining = reader.get_ref().len() - reader.position() as usize;
+    if len.saturating_mul(item_size) > remaining {
+        return Err(SnapshotError::Truncated);
+    }
+    Ok(len)
+}
+
+fn read_bytes(reader: &mut Cursor<&[u8]>) -> Result<Vec<u8>, SnapshotError> {
+    let mut bytes = vec![0; read_len(reader, 1)?];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+impl VM {
+    /// Serializes the complete machine state, including program and fuel settings.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(SNAPSHOT_MAGIC);
+        // Writes into a Vec can't fail
+        bytes.write_u16::<BigEndian>(SNAPSHOT_VERSION).unwrap();
+        bytes.write_u64::<BigEndian>(self.pc as u64).unwrap();
+        for register in self.registers.registers {
+            bytes.write_i32::<BigEndian>(register).unwrap();
+        }
+        for register in self.float_registers.registers {
+            bytes.write_f64::<BigEndian>(register).unwrap();
+        }
+        bytes.write_u32::<BigEndian>(self.remainder).unwrap();
+        bytes.push(self.equal_flag as u8);
+        bytes.push(self.halted as u8);
+        bytes.push(self.fuel.is_some() as u8);
+        bytes.write_u64::<BigEndian>(self.fuel.unwrap_or(0)).unwrap();
+
+        let costs: Vec<(usize, u64)> = (0..self.opcode_costs.len())
+            .filter(|opcode| self.opcode_costs[*opcode] != DEFAULT_OPCODE_COST)
+            .map(|opcode| (opcode, self.opcode_costs[opcode]))
+            .collect();
+        bytes.write_u16::<BigEndian>(costs.len() as u16).unwrap();
+        for (opcode, cost) in costs {
+            bytes.push(opcode as u8);
+            bytes.write_u64::<BigEndian>(cost).unwrap();
+        }
+
+        for data in [&self.program, &self.ro_data, &self.heap] {
+            bytes.write_u32::<BigEndian>(data.len() as u32).unwrap();
+            bytes.extend(data.iter());
+        }
+        bytes.write_u32::<BigEndian>(self.stack.len() as u32).unwrap();
+        for value in &self.stack {
+            bytes.write_i32::<BigEndian>(*value).unwrap();
+        }
+        bytes.write_u32::<BigEndian>(self.call_stack.len() as u32).unwrap();
+        for address in &self.call_stack {
+            bytes.write_u32::<BigEndian>(*address as u32).unwrap();
+        }
+        bytes
+    }
+
+    /// Replaces the machine state with a snapshot. The VM is left untouched if the
+    /// snapshot is invalid. Breakpoints, host functions, logging and any active trace
+    /// or profile are kept, and the undo history is cleared.
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Cursor::new(bytes);
+        let mut magic = [0u8; 4];
+        if reader.read_exact(&mut magic).is_err() || magic != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic {
+                found: bytes.iter().take(4).cloned().collect(),
+            });
+        }
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+
+        let mut vm = VM::new();
+        vm.pc = reader.read_u64::<BigEndian>()? as usize;
+        for register in vm.registers.registers.iter_mut() {
+            *register = reader.read_i32::<BigEndian>()?;
+        }
+        for register in vm.float_registers.registers.iter_mut() {
+            *register = reader.read_f64::<BigEndian>()?;
+        }
+        vm.remainder = reader.read_u32::<BigEndian>()?;
+        vm.equal_flag = reader.read_u8()? != 0;
+        vm.halted = reader.read_u8()? != 0;
+        let metered = reader.read_u8()? != 0;
+        let fuel = reader.read_u64::<BigEndian>()?;
+        vm.fuel = metered.then_some(fuel);
+        for _ in 0..reader.read_u16::<BigEndian>()? {
+            let opcode = reader.read_u8()?;
+            vm.opcode_costs[opcode as usize] = reader.read_u64::<BigEndian>()?;
+        }
+
+        vm.program = read_bytes(&mut reader)?;
+        vm.ro_data = read_bytes(&mut reader)?;
+        vm.heap = read_bytes(&mut reader)?;
+        let len = read_len(&mut reader, 4)?;
+        if len > STACK_SIZE {
+            return Err(SnapshotError::StackTooDeep { len });
+        }
+        for _ in 0..len {
+            vm.stack.push(reader.read_i32::<BigEndian>()?);
+        }
+        let len = read_len(&mut reader, 4)?;
+        if len > CALL_STACK_SIZE {
+            return Err(SnapshotError::CallStackTooDeep { len });
+        }
+        for _ in 0..len {
+            vm.call_stack.push(reader.read_u32::<BigEndian>()? as usize);
+        }
+        let trailing = bytes.len() - reader.position() as usize;
+        if trailing != 0 {
+            return Err(SnapshotError::TrailingData { len: trailing });
+        }
+
+        vm.breakpoints = std::mem::take(&mut self.breakpoints);
+        vm.breakpoints.resume_pc = None;
+        vm.set_history_limit(self.history.limit);
+        vm.host_functions = std::mem::take(&mut self.host_functions);
+        vm.logging = self.logging;
+        vm.tracer = self.tracer.take();
+        vm.profile = self.profile.take();
+        *self = vm;
+        Ok(())
+    }
+}
+
+/// Tests for snapshot
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::OpCode::{self, *};
+    use crate::vm::{Breakpoint, RunOutcome, StopReason, TraceFormat};
+
+    fn busy_vm() -> VM {
+        let mut vm = VM::new();
+        vm.program = vec![
+            LOAD as u8, 0, 0, 7, //
+            LOAD as u8, 1, 0, 2, //
+            DIV as u8, 0, 1, 2, //
+            ALOC as u8, 0, 0, 0, //
+            SETM as u8, 1, 0, 1, //
+            PUSH as u8, 2, 0, 0, //
+            CALL as u8, 0, 28, 0, //
+            INC as u8, 2, 0, 0, //
+            HLT as u8, 0, 0, 0,
+        ];
+        vm.ro_data = b"Hi\0".to_vec();
+        vm.float_registers[3] = -2.5;
+        vm.equal_flag = true;
+        vm.set_fuel(Some(100));
+        vm.set_opcode_cost(OpCode::DIV, 10);
+        // Stop partway, just after CALL
+        assert_eq!(vm.run_limited(7), RunOutcome::BudgetExhausted);
+        vm
+    }
+
+    fn assert_same_state(a: &VM, b: &VM) {
+        assert_eq!(a.pc, b.pc);
+        assert_eq!(a.registers.registers, b.registers.registers);
+        assert_eq!(a.float_registers.registers, b.float_registers.registers);
+        assert_eq!((a.remainder, a.equal_flag, a.halted), (b.remainder, b.equal_flag, b.halted));
+        assert_eq!(a.fuel, b.fuel);
+        assert_eq!(a.opcode_costs, b.opcode_costs);
+        assert_eq!(a.program, b.program);
+        assert_eq!(a.ro_data, b.ro_data);
+        assert_eq!(a.heap, b.heap);
+        assert_eq!(a.stack, b.stack);
+        assert_eq!(a.call_stack, b.call_stack);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() -> Result<(), SnapshotError> {
+        let mut original = busy_vm();
+        let mut restored = VM::new();
+        restored.restore(&original.snapshot())?;
+        assert_same_state(&original, &restored);
+
+        // Both machines carry on identically
+        assert_eq!(original.run(), RunOutcome::Halted);
+        assert_eq!(restored.run(), RunOutcome::Halted);
+        assert_same_state(&original, &restored);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_keeps_breakpoints() -> Result<(), SnapshotError> {
+        let snapshot = busy_vm().snapshot();
+        let mut vm = VM::new();
+        vm.set_history_limit(10);
+        let id = vm.add_breakpoint(Breakpoint::Pc(32));
+        vm.restore(&snapshot)?;
+        assert_eq!(vm.breakpoints().count(), 1);
+        assert!(!vm.step_back());
+        assert_eq!(
+            vm.run(),
+            RunOutcome::Stopped(StopReason::Breakpoint { id, pc: 32 })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_keeps_logging_trace_and_profile() -> Result<(), SnapshotError> {
+        let snapshot = busy_vm().snapshot();
+        let mut vm = VM::new();
+        vm.set_logging(true);
+        vm.start_trace(Box::new(std::io::sink()), TraceFormat::Text).unwrap();
+        vm.enable_profiling();
+        vm.restore(&snapshot)?;
+        assert!(vm.is_tracing());
+        let (_, message) = vm.step().unwrap();
+        assert!(!message.is_empty());
+        assert_eq!(vm.profile().map(|profile| profile.total()), Some(1));
+        vm.stop_trace().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_errors() {
+        let snapshot = busy_vm().snapshot();
+        let mut vm = VM::new();
+        vm.program = vec![HLT as u8, 0, 0, 0];
+
+        assert_eq!(
+            vm.restore(b"IRID"),
+            Err(SnapshotError::BadMagic {
+                found: b"IRID".to_vec()
+            })
+        );
+        let mut bad_version = snapshot.clone();
+        bad_version[5] = 9;
+        assert_eq!(
+            vm.restore(&bad_version),
+            Err(SnapshotError::UnsupportedVersion { version: 9 })
+        );
+        assert_eq!(
+            vm.restore(&snapshot[..snapshot.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+        let mut trailing = snapshot.clone();
+        trailing.push(0);
+        assert_eq!(
+            vm.restore(&trailing),
+            Err(SnapshotError::TrailingData { len: 1 })
+        );
+        // A failed restore leaves the VM as it was
+        assert_eq!(vm.program, vec![HLT as u8, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,560 @@
+use std::{
+    io::{self, Cursor, Read, Write},
+    ops::Range,
+    str::FromStr,
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{VmError, VM};
+use crate::assembler::disassembler::disassemble_instruction;
+use crate::opcode::OpCode;
+
+/// Magic number identifying a binary Iridium trace
+pub const TRACE_MAGIC: [u8; 4] = *b"IRTR";
+/// Current version of the binary trace format
+pub const TRACE_VERSION: u16 = 2;
+
+const FLAG_HEAP_LEN: u8 = 1;
+const FLAG_REMAINDER: u8 = 1 << 1;
+const FLAG_EQUAL: u8 = 1 << 2;
+const FLAG_FAULT: u8 = 1 << 3;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceFormat {
+    /// One line per instruction, like `0008: div $0 $1 $2 | $2: 0 -> 3`
+    Text,
+    /// The compact encoding described on `TraceRecord`
+    Binary,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("Unknown trace format '{}', expected text or binary", s)),
+        }
+    }
+}
+
+/// What one instruction did. Faulting instructions are recorded too, with no effects.
+///
+/// Binary layout (after a `TRACE_MAGIC | TRACE_VERSION` header), all big-endian:
+/// ```text
+/// pc (u32) | instruction length (u8) | instruction bytes | flags (u8)
+/// register count (u8)       | (index (u8) | old (i32) | new (i32))*
+/// float register count (u8) | (index (u8) | old (f64) | new (f64))*
+/// heap write count (u32)    | (address (u32) | length (u32) | bytes)*
+/// [old heap len (u32) | new heap len (u32)]   if flags & FLAG_HEAP_LEN
+/// [old remainder (u32) | new remainder (u32)] if flags & FLAG_REMAINDER
+/// [new equal flag (u8)]                       if flags & FLAG_EQUAL
+/// [fault length (u16) | fault message]        if flags & FLAG_FAULT
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceRecord {
+    pub pc: usize,
+    /// The raw instruction, including any wide operand
+    pub instruction: Vec<u8>,
+    pub registers: Vec<(usize, i32, i32)>,
+    pub float_registers: Vec<(usize, f64, f64)>,
+    /// Bytes written to the heap and the address they start at. For SYSCALL, the
+    /// runs of bytes the host function changed.
+    pub heap_writes: Vec<(usize, Vec<u8>)>,
+    pub heap_len: Option<(usize, usize)>,
+    pub remainder: Option<(u32, u32)>,
+    pub equal_flag: Option<bool>,
+    pub fault: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TraceError {
+    BadMagic { found: Vec<u8> },
+    UnsupportedVersion { version: u16 },
+    Truncated,
+}
+
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TraceError::BadMagic { found } => {
+                write!(f, "Bad magic number {:02X?}, not an Iridium trace", found)
+            }
+            TraceError::UnsupportedVersion { version } => {
+                write!(
+                    f,
+                    "Unsupported trace version {} (supported: {})",
+                    version, TRACE_VERSION
+                )
+            }
+            TraceError::Truncated => write!(f, "Trace is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl From<io::Error> for TraceError {
+    fn from(_: io::Error) -> Self {
+        TraceError::Truncated
+    }
+}
+
+impl std::fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match disassemble_instruction(&self.instruction) {
+            Some((text, _)) => write!(f, "{:04}: {}", self.pc, text)?,
+            None => write!(f, "{:04}: ; {:02X?}", self.pc, self.instruction)?,
+        }
+        for (index, old, new) in &self.registers {
+            write!(f, " | ${}: {} -> {}", index, old, new)?;
+        }
+        for (index, old, new) in &self.float_registers {
+            write!(f, " | $f{}: {:?} -> {:?}", index, old, new)?;
+        }
+        for (address, bytes) in &self.heap_writes {
+            write!(f, " | heap[{}]: {:02X?}", address, bytes)?;
+        }
+        if let Some((old, new)) = self.heap_len {
+            write!(f, " | heap len: {} -> {}", old, new)?;
+        }
+        if let Some((old, new)) = self.remainder {
+            write!(f, " | remainder: {} -> {}", old, new)?;
+        }
+        if let Some(equal) = self.equal_flag {
+            write!(f, " | equal: {}", equal)?;
+        }
+        if let Some(fault) = &self.fault {
+            write!(f, " | fault: {}", fault)?;
+        }
+        Ok(())
+    }
+}
+
+impl TraceRecord {
+    pub fn write_binary<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let flags = (self.heap_len.is_some() as u8 * FLAG_HEAP_LEN)
+            | (self.remainder.is_some() as u8 * FLAG_REMAINDER)
+            | (self.equal_flag.is_some() as u8 * FLAG_EQUAL)
+            | (self.fault.is_some() as u8 * FLAG_FAULT);
+        out.write_u32::<BigEndian>(self.pc as u32)?;
+        out.write_u8(self.instruction.len() as u8)?;
+        out.write_all(&self.instruction)?;
+        out.write_u8(flags)?;
+        out.write_u8(self.registers.len() as u8)?;
+        for (index, old, new) in &self.registers {
+            out.write_u8(*index as u8)?;
+            out.write_i32::<BigEndian>(*old)?;
+            out.write_i32::<BigEndian>(*new)?;
+        }
+        out.write_u8(self.float_registers.len() as u8)?;
+        for (index, old, new) in &self.float_registers {
+            out.write_u8(*index as u8)?;
+            out.write_f64::<BigEndian>(*old)?;
+            out.write_f64::<BigEndian>(*new)?;
+        }
+        out.write_u32::<BigEndian>(self.heap_writes.len() as u32)?;
+        for (address, bytes) in &self.heap_writes {
+            out.write_u32::<BigEndian>(*address as u32)?;
+            out.write_u32::<BigEndian>(bytes.len() as u32)?;
+            out.write_all(bytes)?;
+        }
+        if let Some((old, new)) = self.heap_len {
+            out.write_u32::<BigEndian>(old as u32)?;
+            out.write_u32::<BigEndian>(new as u32)?;
+        }
+        if let Some((old, new)) = self.remainder {
+            out.write_u32::<BigEndian>(old)?;
+            out.write_u32::<BigEndian>(new)?;
+        }
+        if let Some(equal) = self.equal_flag {
+            out.write_u8(equal as u8)?;
+        }
+        if let Some(fault) = &self.fault {
+            out.write_u16::<BigEndian>(fault.len() as u16)?;
+            out.write_all(fault.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn read_binary(reader: &mut Cursor<&[u8]>) -> Result<Self, TraceError> {
+        let pc = reader.read_u32::<BigEndian>()? as usize;
+        let mut instruction = vec![0; reader.read_u8()? as usize];
+        reader.read_exact(&mut instruction)?;
+        let flags = reader.read_u8()?;
+        let mut registers = vec![];
+        for _ in 0..reader.read_u8()? {
+            let index = reader.read_u8()? as usize;
+            let old = reader.read_i32::<BigEndian>()?;
+            registers.push((index, old, reader.read_i32::<BigEndian>()?));
+        }
+        let mut float_registers = vec![];
+        for _ in 0..reader.read_u8()? {
+            let index = reader.read_u8()? as usize;
+            let old = reader.read_f64::<BigEndian>()?;
+            float_registers.push((index, old, reader.read_f64::<BigEndian>()?));
+        }
+        let mut heap_writes = vec![];
+        for _ in 0..reader.read_u32::<BigEndian>()? {
+            let address = reader.read_u32::<BigEndian>()? as usize;
+            let len = reader.read_u32::<BigEndian>()? as usize;
+            let remaining = reader.get_ref().len() - reader.position() as usize;
+            if len > remaining {
+                return Err(TraceError::Truncated);
+            }
+            let mut bytes = vec![0; len];
+            reader.read_exact(&mut bytes)?;
+            heap_writes.push((address, bytes));
+        }
+        let heap_len = match flags & FLAG_HEAP_LEN {
+            0 => None,
+            _ => Some((
+                reader.read_u32::<BigEndian>()? as usize,
+                reader.read_u32::<BigEndian>()? as usize,
+            )),
+        };
+        let remainder = match flags & FLAG_REMAINDER {
+            0 => None,
+            _ => Some((
+                reader.read_u32::<BigEndian>()?,
+                reader.read_u32::<BigEndian>()?,
+            )),
+        };
+        let equal_flag = match flags & FLAG_EQUAL {
+            0 => None,
+            _ => Some(reader.read_u8()? != 0),
+        };
+        let fault = match flags & FLAG_FAULT {
+            0 => None,
+            _ => {
+                let mut message = vec![0; reader.read_u16::<BigEndian>()? as usize];
+                reader.read_exact(&mut message)?;
+                Some(String::from_utf8_lossy(&message).into_owned())
+            }
+        };
+        Ok(Self {
+            pc,
+            instruction,
+            registers,
+            float_registers,
+            heap_writes,
+            heap_len,
+            remainder,
+            equal_flag,
+            fault,
+        })
+    }
+}
+
+/// Decodes a whole binary trace.
+pub fn read_binary_trace(bytes: &[u8]) -> Result<Vec<TraceRecord>, TraceError> {
+    let mut reader = Cursor::new(bytes);
+    let mut magic = [0u8; 4];
+    if reader.read_exact(&mut magic).is_err() || magic != TRACE_MAGIC {
+        return Err(TraceError::BadMagic {
+            found: bytes.iter().take(4).cloned().collect(),
+        });
+    }
+    let version = reader.read_u16::<BigEndian>()?;
+    if version != TRACE_VERSION {
+        return Err(TraceError::UnsupportedVersion { version });
+    }
+    let mut records = vec![];
+    while (reader.position() as usize) < bytes.len() {
+        records.push(TraceRecord::read_binary(&mut reader)?);
+    }
+    Ok(records)
+}
+
+/// Where trace records go. A write error stops tracing and is reported by `VM::stop_trace`.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    error: Option<io::Error>,
+}
+
+impl std::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+/// State captured before an instruction runs, to work out what it changed
+pub(super) struct PendingTrace {
+    pc: usize,
+    registers: [i32; 32],
+    float_registers: [f64; 32],
+    heap_len: usize,
+    heap_write: Option<Range<usize>>,
+    /// The whole heap before a SYSCALL, since the host function may change any of it
+    heap_before_syscall: Option<Vec<u8>>,
+    remainder: u32,
+    equal_flag: bool,
+}
+
+impl VM {
+    /// Starts writing a record for every instruction executed from now on.
+    /// Binary traces begin with a header, which is written straight away.
+    pub fn start_trace(&mut self, mut out: Box<dyn Write>, format: TraceFormat) -> io::Result<()> {
+        self.stop_trace()?;
+        if format == TraceFormat::Binary {
+            out.write_all(&TRACE_MAGIC)?;
+            out.write_u16::<BigEndian>(TRACE_VERSION)?;
+        }
+        self.tracer = Some(Tracer {
+            out,
+            format,
+            error: None,
+        });
+        Ok(())
+    }
+
+    /// Flushes and closes the trace, reporting the first write error if there was one.
+    pub fn stop_trace(&mut self) -> io::Result<()> {
+        match self.tracer.take() {
+            Some(Tracer {
+                error: Some(e), ..
+            }) => Err(e),
+            Some(mut tracer) => tracer.out.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    pub(super) fn begin_trace(&self) -> Option<PendingTrace> {
+        if self.tracer.is_none() || self.pc >= self.program.len() {
+            return None;
+        }
+        Some(PendingTrace {
+            pc: self.pc,
+            registers: self.registers.registers,
+            float_registers: self.float_registers.registers,
+            heap_len: self.heap.len(),
+            heap_write: self.pending_heap_write(),
+            heap_before_syscall: (self.program[self.pc] == OpCode::SYSCALL as u8)
+                .then(|| self.heap.clone()),
+            remainder: self.remainder,
+            equal_flag: self.equal_flag,
+        })
+    }
+
+    pub(super) fn record_trace(&mut self, pending: PendingTrace, fault: Option<&VmError>) {
+        let size = if self.program[pending.pc] == OpCode::LOADF64 as u8 {
+            12
+        } else {
+            4
+        };
+        let end = (pending.pc + size).min(self.program.len());
+        let mut record = TraceRecord {
+            pc: pending.pc,
+            instruction: self.program[pending.pc..end].to_vec(),
+            registers: vec![],
+            float_registers: vec![],
+            heap_writes: vec![],
+            heap_len: None,
+            remainder: None,
+            equal_flag: None,
+            fault: fault.map(|e| e.kind.to_string()),
+        };
+        if fault.is_none() {
+            record.registers = (0..pending.registers.len())
+                .filter(|i| pending.registers[*i] != self.registers.registers[*i])
+                .map(|i| (i, pending.registers[i], self.registers.registers[i]))
+                .collect();
+            record.float_registers = (0..pending.float_registers.len())
+                .filter(|i| {
+                    pending.float_registers[*i].to_bits()
+                        != self.float_registers.registers[*i].to_bits()
+                })
+                .map(|i| {
+                    let new = self.float_registers.registers[i];
+                    (i, pending.float_registers[i], new)
+                })
+                .collect();
+            if let Some(range) = pending.heap_write {
+                record.heap_writes = vec![(range.start, self.heap[range].to_vec())];
+            }
+            if let Some(old) = pending.heap_before_syscall {
+                record.heap_writes = changed_runs(&old, &self.heap);
+            }
+            if pending.heap_len != self.heap.len() {
+                record.heap_len = Some((pending.heap_len, self.heap.len()));
+            }
+            if pending.remainder != self.remainder {
+                record.remainder = Some((pending.remainder, self.remainder));
+            }
+            if pending.equal_flag != self.equal_flag {
+                record.equal_flag = Some(self.equal_flag);
+            }
+        }
+
+        let tracer = match self.tracer.as_mut() {
+            Some(tracer) if tracer.error.is_none() => tracer,
+            _ => return,
+        };
+        let written = match tracer.format {
+            TraceFormat::Text => writeln!(tracer.out, "{}", record),
+            TraceFormat::Binary => record.write_binary(&mut tracer.out),
+        };
+        if let Err(e) = written {
+            tracer.error = Some(e);
+        }
+    }
+}
+
+/// The runs of consecutive bytes that differ between `old` and `new`. Bytes the heap
+/// grew by count as changed unless they're zero, as ALOC would have left them.
+fn changed_runs(old: &[u8], new: &[u8]) -> Vec<(usize, Vec<u8>)> {
+    let mut runs: Vec<(usize, Vec<u8>)> = vec![];
+    for (address, byte) in new.iter().enumerate() {
+        if old.get(address).copied().unwrap_or(0) == *byte {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((start, bytes)) if *start + bytes.len() == address => bytes.push(*byte),
+            _ => runs.push((address, vec![*byte])),
+        }
+    }
+    runs
+}
+
+/// Tests for trace
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opcode::OpCode::*;
+    use crate::vm::RunOutcome;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// A writer whose contents can be read back after the VM takes ownership of it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn traced_vm(format: TraceFormat) -> (VM, SharedBuffer) {
+        let mut vm = VM::new();
+        vm.program = vec![
+            LOAD as u8, 0, 0, 7, //
+            LOAD as u8, 1, 0, 2, //
+            DIV as u8, 0, 1, 2, //
+            ALOC as u8, 0, 0, 0, //
+            SETM as u8, 1, 0, 1, //
+            EQ as u8, 0, 0, 0, //
+            DIV as u8, 0, 3, 4,
+        ];
+        let buffer = SharedBuffer::default();
+        vm.start_trace(Box::new(buffer.clone()), format).unwrap();
+        (vm, buffer)
+    }
+
+    #[test]
+    fn test_text_trace() {
+        let (mut vm, buffer) = traced_vm(TraceFormat::Text);
+        assert!(matches!(vm.run(), RunOutcome::Faulted(_)));
+        vm.stop_trace().unwrap();
+        assert!(!vm.is_tracing());
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(
+            trace,
+            "0000: load $0 #7 | $0: 0 -> 7\n\
+             0004: load $1 #2 | $1: 0 -> 2\n\
+             0008: div $0 $1 $2 | $2: 0 -> 3 | remainder: 0 -> 1\n\
+             0012: aloc $0 | heap len: 0 -> 7\n\
+             0016: setm $1 $0 #1 | heap[2]: [07]\n\
+             0020: eq $0 $0 | equal: true\n\
+             0024: div $0 $3 $4 | fault: Division by zero\n"
+        );
+    }
+
+    #[test]
+    fn test_binary_trace_round_trip() {
+        let (mut vm, buffer) = traced_vm(TraceFormat::Binary);
+        vm.run();
+        vm.stop_trace().unwrap();
+        let records = read_binary_trace(&buffer.0.borrow()).unwrap();
+        assert_eq!(records.len(), 7);
+        assert_eq!(records[4].heap_writes, vec![(2, vec![7])]);
+        assert_eq!(records[6].fault, Some("Division by zero".to_string()));
+
+        // Decoding gives the same records as the text trace
+        let (mut vm, text) = traced_vm(TraceFormat::Text);
+        vm.run();
+        let decoded: String = records.iter().map(|r| format!("{}\n", r)).collect();
+        assert_eq!(decoded.as_bytes(), &text.0.borrow()[..]);
+    }
+
+    #[test]
+    fn test_binary_trace_errors() {
+        assert_eq!(
+            read_binary_trace(b"IRSN"),
+            Err(TraceError::BadMagic {
+                found: b"IRSN".to_vec()
+            })
+        );
+        assert_eq!(
+            read_binary_trace(&[b'I', b'R', b'T', b'R', 0, 9]),
+            Err(TraceError::UnsupportedVersion { version: 9 })
+        );
+        assert_eq!(
+            read_binary_trace(&[b'I', b'R', b'T', b'R', 0, 2, 0, 0]),
+            Err(TraceError::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_float_trace() {
+        let mut vm = VM::new();
+        vm.program = vec![LOADF64 as u8, 1, 0, 0];
+        vm.program.extend(1.5f64.to_be_bytes());
+        let buffer = SharedBuffer::default();
+        vm.start_trace(Box::new(buffer.clone()), TraceFormat::Text)
+            .unwrap();
+        vm.run();
+        assert_eq!(
+            String::from_utf8(buffer.0.borrow().clone()).unwrap(),
+            "0000: loadf64 $f1 #1.5 | $f1: 0.0 -> 1.5\n"
+        );
+    }
+
+    #[test]
+    fn test_syscall_heap_writes() {
+        let mut vm = VM::new();
+        vm.register_host_function(9, |ctx| {
+            ctx.heap[1] = 0xAA;
+            ctx.heap[2] = 0xBB;
+            ctx.heap[4] = 0xCC;
+            ctx.heap.extend([0, 7]);
+            Ok(None)
+        });
+        vm.heap = vec![0; 5];
+        vm.program = vec![SYSCALL as u8, 0, 9, 0];
+        let buffer = SharedBuffer::default();
+        vm.start_trace(Box::new(buffer.clone()), TraceFormat::Binary)
+            .unwrap();
+        vm.run();
+        vm.stop_trace().unwrap();
+        let records = read_binary_trace(&buffer.0.borrow()).unwrap();
+        assert_eq!(
+            records[0].heap_writes,
+            vec![(1, vec![0xAA, 0xBB]), (4, vec![0xCC]), (6, vec![7])]
+        );
+        assert_eq!(records[0].heap_len, Some((5, 7)));
+    }
+}